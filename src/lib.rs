@@ -0,0 +1,5786 @@
+#![deny(missing_docs)]
+
+//! A basic post-processor for adding Prusaslicer gcode attributes to the beginning of
+//! gcode files to help the Ankermake M5 printer to correctly estimate print times and
+//! material usage.
+
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{stdin, stdout, BufRead, BufReader, BufWriter, ErrorKind, Write};
+use std::iter::Peekable;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::{Chars, FromStr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Prusaslicer attribute for the estimated printing time. Formatted as "XXh YYm ZZs" string, with a leading "Xd" for
+/// prints lasting days
+pub const PRUSA_ESTIMATED_PRINTING_TIME: &str = "estimated printing time";
+/// Mode of the estimated printing time that is used when Prusaslicer also estimates it for silent mode
+pub const PRUSA_NORMAL_MODE: &str = "(normal mode)";
+/// Prusaslicer attribute for the estimated material usage. Formatted in millimeters, to 2 decimal places
+pub const PRUSA_FILAMENT_USED_MM: &str = "filament used [mm]";
+/// Prusaslicer attribute for the estimated weight of material used. Formatted in grams, to 2 decimal places, one comma
+/// separated value per extruder. Only written when the filament's density is set.
+pub const PRUSA_FILAMENT_USED_G: &str = "filament used [g]";
+/// Attribute older Slic3r Prusa Edition versions used for the estimated material usage, before the unit was added to the
+/// key. Formatted in millimeters with the volume after it, e.g. "1234.5mm (3.0cm3)". Only used when the modern
+/// [`PRUSA_FILAMENT_USED_MM`] attribute is missing. The legacy "estimated printing time (normal mode)" attribute is already
+/// matched by [`PRUSA_ESTIMATED_PRINTING_TIME`].
+pub const PRUSA_LEGACY_FILAMENT_USED: &str = "filament used";
+/// Prusaslicer config attribute for the gcode flavour it generated, e.g. "marlin2"
+pub const PRUSA_GCODE_FLAVOR: &str = "gcode_flavor";
+/// Prusaslicer config attribute for printing the external perimeter before the inner ones. Formatted as 0 or 1.
+pub const PRUSA_EXTERNAL_PERIMETERS_FIRST: &str = "external_perimeters_first";
+/// Prusaslicer config attribute for printing infill before the perimeters. Formatted as 0 or 1.
+pub const PRUSA_INFILL_FIRST: &str = "infill_first";
+/// Prusaslicer config attribute for the height of the top of the print, in millimeters, recorded by newer versions
+pub const PRUSA_MAX_LAYER_Z: &str = "max_layer_z";
+/// Prusaslicer config attribute for the bed temperature in degrees Celsius after the first layer, one comma separated
+/// value per extruder. The first layer has its own attribute, but the rest of the print is what the header describes.
+pub const PRUSA_BED_TEMPERATURE: &str = "bed_temperature";
+/// Prusaslicer config attribute for the hotend temperature in degrees Celsius after the first layer, one comma separated
+/// value per extruder
+pub const PRUSA_TEMPERATURE: &str = "temperature";
+/// Prusaslicer config attribute for the type of filament, e.g. "PLA", one semicolon separated value per extruder
+pub const PRUSA_FILAMENT_TYPE: &str = "filament_type";
+/// Usual hotend and bed temperatures in degrees Celsius for each Prusaslicer filament type, used in the header when the
+/// config records the filament type but not the temperatures
+pub const DEFAULT_TEMPERATURES: [(&str, u64, u64); 7] = [
+    ("PLA", 210, 60),
+    ("PETG", 240, 85),
+    ("ABS", 255, 100),
+    ("ASA", 260, 105),
+    ("FLEX", 240, 50),
+    ("PC", 275, 110),
+    ("PA", 285, 90),
+];
+/// Prusaslicer config attribute for the nozzle diameter in millimeters, one comma separated value per extruder
+pub const PRUSA_NOZZLE_DIAMETER: &str = "nozzle_diameter";
+/// Prusaslicer config attribute for the filament diameter in millimeters, one comma separated value per extruder
+pub const PRUSA_FILAMENT_DIAMETER: &str = "filament_diameter";
+/// Prusaslicer config attribute for the filament density in grams per cubic centimeter, one comma separated value per
+/// extruder. Left as 0 when it isn't set.
+pub const PRUSA_FILAMENT_DENSITY: &str = "filament_density";
+/// Filament diameter in millimeters used to estimate the filament weight when the config doesn't record it
+pub const DEFAULT_FILAMENT_DIAMETER_MM: f64 = 1.75;
+/// Usual density in grams per cubic centimeter for each Prusaslicer filament type, used to estimate the filament weight
+/// when the config records the filament type but not its density
+pub const DEFAULT_FILAMENT_DENSITIES: [(&str, f64); 7] = [
+    ("PLA", 1.24),
+    ("PETG", 1.27),
+    ("ABS", 1.04),
+    ("ASA", 1.07),
+    ("FLEX", 1.21),
+    ("PC", 1.20),
+    ("PA", 1.14),
+];
+/// Smallest nozzle diameter, in millimeters, that is plausible for a real printer
+pub const MIN_PLAUSIBLE_NOZZLE_DIAMETER: f64 = 0.1;
+/// Largest fraction the filament extruded by the moves can differ from Prusaslicer's estimate by before it is warned about
+pub const FILAMENT_MISMATCH_TOLERANCE: f64 = 0.1;
+/// Prusaslicer attribute holding a JSON description of every object on the bed, written by newer versions
+pub const PRUSA_OBJECTS_INFO: &str = "objects_info";
+/// Prusaslicer comment at the top of the file recording the version that generated it, e.g.
+/// "; generated by PrusaSlicer 2.6.0+linux-x64-GTK3 on 2023-06-27 at 10:00:00 UTC"
+pub const PRUSA_GENERATED_BY: &str = "; generated by PrusaSlicer ";
+/// Newest Prusaslicer major and minor version this post-processor has been checked against. Newer versions may have
+/// changed the attributes it relies on.
+pub const LATEST_TESTED_PRUSASLICER_VERSION: (u64, u64) = (2, 7);
+/// Prusaslicer comment marking the start of each new layer
+pub const PRUSA_LAYER_CHANGE: &str = ";LAYER_CHANGE";
+/// Prusaslicer comment recording the height of the layer that has just started
+pub const PRUSA_LAYER_Z: &str = ";Z:";
+/// Prusaslicer comment placed before the gcode for a color change, e.g. ";COLOR_CHANGE,T0,#FF8000"
+pub const PRUSA_COLOR_CHANGE: &str = ";COLOR_CHANGE";
+/// Instruction to pause for a filament change, which Prusaslicer uses for color changes
+pub const GCODE_FILAMENT_CHANGE: &str = "M600";
+/// Instruction switching the extruder to absolute positioning, Marlin's default
+pub const GCODE_ABSOLUTE_EXTRUSION: &str = "M82";
+/// Instruction switching the extruder to relative positioning
+pub const GCODE_RELATIVE_EXTRUSION: &str = "M83";
+/// Instruction setting the current position without moving, e.g. "G92 E0" to reset the extruder
+pub const GCODE_SET_POSITION: &str = "G92";
+/// Instruction switching the X, Y and Z axes to absolute positioning, Marlin's default
+pub const GCODE_ABSOLUTE_POSITIONING: &str = "G90";
+/// Instruction switching the X, Y and Z axes to relative positioning
+pub const GCODE_RELATIVE_POSITIONING: &str = "G91";
+/// Instruction homing the axes it names, or every axis if it names none
+pub const GCODE_HOME: &str = "G28";
+/// Prusaslicer comment opening the config block at the end of the file
+pub const PRUSA_CONFIG_BEGIN: &str = "prusaslicer_config = begin";
+/// Prusaslicer comment closing the config block, the very last line of a complete file
+pub const PRUSA_CONFIG_END: &str = "prusaslicer_config = end";
+/// Prusaslicer attribute for the number of toolchanges in a multi-material print. Formatted as an integer.
+pub const PRUSA_TOTAL_TOOLCHANGES: &str = "total toolchanges";
+/// Prusaslicer comment opening a PNG thumbnail, followed by its size and the length of its base64 payload, e.g.
+/// "; thumbnail begin 300x300 12345". The payload follows as comment lines, up to [`PRUSA_THUMBNAIL_END`].
+pub const PRUSA_THUMBNAIL_BEGIN: &str = "thumbnail begin";
+/// Prusaslicer comment closing a thumbnail
+pub const PRUSA_THUMBNAIL_END: &str = "thumbnail end";
+/// Magic bytes at the very start of a PNG image
+pub const PNG_MAGIC: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Ankermake attribute for the estimated printing time. Formatted as integer number of seconds.
+pub const ANKERMAKE_PRINTING_TIME: &str = "TIME";
+/// Ankermake attribute for the estimated material usage. Formatted in meters to 5 decimal places.
+pub const ANKERMAKE_FILAMENT_USED_M: &str = "Filament used";
+/// Ankermake attribute for the estimated weight of material used. Formatted in grams to 2 decimal places.
+pub const ANKERMAKE_FILAMENT_USED_G: &str = "Filament used [g]";
+/// Comment written just before the [`ANKERMAKE_FILAMENT_USED_G`] header field when the weight was worked out from the
+/// length of filament used, rather than taken from Prusaslicer
+pub const ANKERMAKE_ESTIMATED_WEIGHT_NOTE: &str =
+    "; Filament used [g] estimated from the filament's length, diameter and density";
+/// The gcode flavour, always Marlin
+pub const ANKERMAKE_FLAVOUR: &str = "FLAVOR";
+/// Records which post-processor, and which version of it, last touched the file
+pub const ANKERMAKE_POST_PROCESSOR: &str = "PostProcessor";
+/// Ankermake attribute for the large preview image, shown before printing. Formatted as a base64 encoded PNG on a single
+/// line, the same encoding Prusaslicer uses for its thumbnails.
+pub const ANKERMAKE_LARGE_THUMBNAIL: &str = "gimage";
+/// Ankermake attribute for the small preview image, shown in the file list. Formatted like [`ANKERMAKE_LARGE_THUMBNAIL`].
+pub const ANKERMAKE_SMALL_THUMBNAIL: &str = "simage";
+/// Ankermake attribute for the bed temperature. Formatted as an integer number of degrees Celsius.
+pub const ANKERMAKE_BED_TEMPERATURE: &str = "BEDTEMP";
+/// Ankermake attribute for the hotend temperature. Formatted as an integer number of degrees Celsius.
+pub const ANKERMAKE_HOTEND_TEMPERATURE: &str = "HOTENDTEMP";
+/// Ankermake attribute for the number of layers in the print. Formatted as an integer.
+pub const ANKERMAKE_LAYER_COUNT: &str = "LAYER_COUNT";
+/// Ankermake attributes for the bounds of everything the print extrudes. Formatted in millimeters to 2 decimal places.
+pub const ANKERMAKE_MIN_X: &str = "MINX";
+/// See [`ANKERMAKE_MIN_X`]
+pub const ANKERMAKE_MAX_X: &str = "MAXX";
+/// See [`ANKERMAKE_MIN_X`]
+pub const ANKERMAKE_MIN_Y: &str = "MINY";
+/// See [`ANKERMAKE_MIN_X`]
+pub const ANKERMAKE_MAX_Y: &str = "MAXY";
+/// See [`ANKERMAKE_MIN_X`]
+pub const ANKERMAKE_MIN_Z: &str = "MINZ";
+/// See [`ANKERMAKE_MIN_X`]
+pub const ANKERMAKE_MAX_Z: &str = "MAXZ";
+/// Name this post-processor stamps into the header alongside its version
+pub const POST_PROCESSOR_NAME: &str = "prusa2anker";
+/// Opens the grouped print info block that some M5 firmware reads the header fields from
+pub const ANKERMAKE_INFO_BLOCK_BEGIN: &str = ";INFO_BLOCK begin";
+/// Closes the grouped print info block
+pub const ANKERMAKE_INFO_BLOCK_END: &str = ";INFO_BLOCK end";
+/// Opens the user's start gcode, marking it so that it can be replaced rather than repeated when a file is processed again
+pub const START_GCODE_BEGIN: &str = ";START_GCODE begin";
+/// Closes the user's start gcode
+pub const START_GCODE_END: &str = ";START_GCODE end";
+/// The gcode flavour used when Prusaslicer's own flavour is missing or has no Ankermake equivalent
+pub const ANKERMAKE_DEFAULT_FLAVOUR: &str = "Marlin";
+
+/// Magic bytes at the very start of a Prusaslicer binary gcode file
+pub const BGCODE_MAGIC: &[u8] = b"GCDE";
+/// File extension Prusaslicer uses for binary gcode
+pub const BGCODE_EXTENSION: &str = "bgcode";
+/// Byte order mark that some editors put at the very start of UTF-8 files
+pub const UTF8_BOM: char = '\u{feff}';
+/// Magic bytes at the very start of a gzip compressed file
+pub const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+/// File extension for plain text gcode, which is what's looked for when searching a directory
+pub const GCODE_EXTENSION: &str = "gcode";
+/// File extension for gzip compressed files, e.g. "print.gcode.gz"
+pub const GZIP_EXTENSION: &str = "gz";
+/// Magic bytes at the very start of a zstd compressed file
+pub const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+/// File extension for zstd compressed files, e.g. "print.gcode.zst"
+pub const ZSTD_EXTENSION: &str = "zst";
+
+/// Extension appended to the gcode file's path to name its header sidecar file
+pub const HEADER_SIDECAR_EXTENSION: &str = "header";
+
+/// Extension appended to the gcode file's path to name its classification trace file
+pub const TRACE_EXTENSION: &str = "trace";
+
+/// Path standing in for stdin as an input file, or stdout as the output file
+pub const STDIO_PATH: &str = "-";
+
+/// Default upper bound on the length of a single line, in bytes. Anything longer is never buffered in full.
+pub const DEFAULT_MAX_LINE_LENGTH: usize = 1024 * 1024;
+/// Smallest upper bound on the length of a single line that can be configured, in bytes. Anything lower would cut off
+/// ordinary Prusaslicer config lines.
+pub const SMALLEST_MAX_LINE_LENGTH: usize = 1024;
+
+/// Potential errors that can be encountered while parsing the gcode
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParsingError {
+    /// While attempting to extract a value from a line, no value was found
+    MissingValue(String),
+    /// An attempt to parse a string into the specified type failed
+    StringParsingError(&'static str, String),
+    /// The gcode couldn't be read, or the converted gcode couldn't be written out, with the OS error
+    Io(String),
+    /// A line was longer than the maximum line length, so the file couldn't be read in full
+    LineTooLong {
+        /// Line number, counting from 1
+        line: usize,
+        /// The maximum line length, in bytes
+        max_line_length: usize,
+    },
+    /// Every metadata line the header depends on that couldn't be parsed, along with its line number counting from 1
+    InvalidLines(Vec<(usize, ParsingError)>),
+}
+
+impl std::fmt::Display for ParsingError {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParsingError::MissingValue(attribute) => {
+                write!(formatter, "no value found in \"{attribute}\"")
+            }
+            ParsingError::StringParsingError(type_name, value) => {
+                write!(formatter, "\"{value}\" is not a valid {type_name}")
+            }
+            ParsingError::Io(error) => {
+                write!(formatter, "failed to read or write the gcode: {error}")
+            }
+            ParsingError::LineTooLong {
+                line,
+                max_line_length,
+            } => write!(
+                formatter,
+                "line {line} is longer than {max_line_length} bytes, raise the maximum line length or pass --lenient to truncate it"
+            ),
+            ParsingError::InvalidLines(errors) => {
+                for (index, (line, error)) in errors.iter().enumerate() {
+                    if index > 0 {
+                        write!(formatter, "; ")?;
+                    }
+                    write!(formatter, "line {line}: {error}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParsingError {}
+
+/// The line ending a file uses, which the converted file keeps so that it doesn't end up with a mix of both.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LineEnding {
+    /// Unix style `\n`, also used for empty files
+    #[default]
+    Lf,
+    /// Windows style `\r\n`
+    CrLf,
+}
+
+impl LineEnding {
+    /// The characters that end each line
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+        }
+    }
+}
+
+/// How the lines of a file were laid out, which the converted file keeps so that it only differs by what was converted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TextLayout {
+    /// The line ending used by most of the lines
+    pub line_ending: LineEnding,
+    /// Whether the last line was followed by a line ending. False for empty files.
+    pub trailing_newline: bool,
+}
+
+/// The unit that the amount of filament used is emitted in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FilamentUnit {
+    /// Meters, to 5 decimal places. What the stock M5 firmware expects.
+    #[default]
+    Meters,
+    /// Millimeters, to 2 decimal places, for firmware that wants the unconverted value.
+    Millimeters,
+}
+
+impl FromStr for FilamentUnit {
+    type Err = String;
+
+    fn from_str(unit: &str) -> Result<Self, Self::Err> {
+        match unit {
+            "m" => Ok(FilamentUnit::Meters),
+            "mm" => Ok(FilamentUnit::Millimeters),
+            _ => Err(format!(
+                "Unknown filament unit \"{unit}\", expected \"m\" or \"mm\""
+            )),
+        }
+    }
+}
+
+/// When the converted file is written back over the original.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverwritePolicy {
+    /// Always write the converted file
+    #[default]
+    Always,
+    /// Only write the converted file if it differs from the original, to avoid needless writes to SD cards
+    IfChanged,
+    /// Never write the converted file, leaving the original untouched
+    Never,
+}
+
+impl FromStr for OverwritePolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "always" => Ok(OverwritePolicy::Always),
+            "if-changed" => Ok(OverwritePolicy::IfChanged),
+            "never" => Ok(OverwritePolicy::Never),
+            _ => Err(format!(
+                "Unknown overwrite policy \"{policy}\", expected \"always\", \"if-changed\" or \"never\""
+            )),
+        }
+    }
+}
+
+/// An arithmetic expression for adjusting the estimated printing time, in terms of the estimated time in seconds `t`,
+/// e.g. "t * 1.1 + 300". Supports numbers, `t`, `+`, `-`, `*`, `/` and parentheses, and nothing else.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TimeFormula {
+    /// The estimated printing time, in seconds
+    Seconds,
+    /// A constant
+    Number(f64),
+    /// The negation of an expression
+    Negate(Box<TimeFormula>),
+    /// The sum of two expressions
+    Add(Box<TimeFormula>, Box<TimeFormula>),
+    /// The difference of two expressions
+    Subtract(Box<TimeFormula>, Box<TimeFormula>),
+    /// The product of two expressions
+    Multiply(Box<TimeFormula>, Box<TimeFormula>),
+    /// The quotient of two expressions
+    Divide(Box<TimeFormula>, Box<TimeFormula>),
+}
+
+impl TimeFormula {
+    /// Evaluate the formula for the given estimated time, rounding to whole seconds. Anything that isn't a positive
+    /// number of seconds, such as a division by zero, comes out as zero.
+    pub fn apply(&self, seconds: u64) -> u64 {
+        let adjusted_seconds: f64 = self.evaluate(seconds as f64).round();
+        if adjusted_seconds.is_finite() && adjusted_seconds > 0.0 {
+            adjusted_seconds as u64
+        } else {
+            0
+        }
+    }
+
+    fn evaluate(&self, seconds: f64) -> f64 {
+        use TimeFormula::*;
+        match self {
+            Seconds => seconds,
+            Number(number) => *number,
+            Negate(operand) => -operand.evaluate(seconds),
+            Add(left, right) => left.evaluate(seconds) + right.evaluate(seconds),
+            Subtract(left, right) => left.evaluate(seconds) - right.evaluate(seconds),
+            Multiply(left, right) => left.evaluate(seconds) * right.evaluate(seconds),
+            Divide(left, right) => left.evaluate(seconds) / right.evaluate(seconds),
+        }
+    }
+
+    /// Parse a sum or difference of terms
+    fn parse_sum(characters: &mut Peekable<Chars>) -> Result<Self, String> {
+        let mut formula: TimeFormula = Self::parse_product(characters)?;
+        loop {
+            Self::skip_whitespace(characters);
+            if characters.next_if_eq(&'+').is_some() {
+                formula = TimeFormula::Add(
+                    Box::new(formula),
+                    Box::new(Self::parse_product(characters)?),
+                );
+            } else if characters.next_if_eq(&'-').is_some() {
+                formula = TimeFormula::Subtract(
+                    Box::new(formula),
+                    Box::new(Self::parse_product(characters)?),
+                );
+            } else {
+                return Ok(formula);
+            }
+        }
+    }
+
+    /// Parse a product or quotient of factors
+    fn parse_product(characters: &mut Peekable<Chars>) -> Result<Self, String> {
+        let mut formula: TimeFormula = Self::parse_factor(characters)?;
+        loop {
+            Self::skip_whitespace(characters);
+            if characters.next_if_eq(&'*').is_some() {
+                formula = TimeFormula::Multiply(
+                    Box::new(formula),
+                    Box::new(Self::parse_factor(characters)?),
+                );
+            } else if characters.next_if_eq(&'/').is_some() {
+                formula = TimeFormula::Divide(
+                    Box::new(formula),
+                    Box::new(Self::parse_factor(characters)?),
+                );
+            } else {
+                return Ok(formula);
+            }
+        }
+    }
+
+    /// Parse a number, `t`, a negated factor or a parenthesised sum
+    fn parse_factor(characters: &mut Peekable<Chars>) -> Result<Self, String> {
+        Self::skip_whitespace(characters);
+        match characters.peek() {
+            Some('t') => {
+                characters.next();
+                Ok(TimeFormula::Seconds)
+            }
+            Some('-') => {
+                characters.next();
+                Ok(TimeFormula::Negate(Box::new(Self::parse_factor(
+                    characters,
+                )?)))
+            }
+            Some('(') => {
+                characters.next();
+                let formula: TimeFormula = Self::parse_sum(characters)?;
+                Self::skip_whitespace(characters);
+                characters
+                    .next_if_eq(&')')
+                    .ok_or_else(|| "missing closing parenthesis".to_string())?;
+                Ok(formula)
+            }
+            Some(character) if character.is_ascii_digit() || *character == '.' => {
+                let mut number: String = String::new();
+                while let Some(character) =
+                    characters.next_if(|character| character.is_ascii_digit() || *character == '.')
+                {
+                    number.push(character);
+                }
+                number
+                    .parse()
+                    .map(TimeFormula::Number)
+                    .map_err(|_| format!("invalid number \"{number}\""))
+            }
+            Some(character) => Err(format!("unexpected \"{character}\"")),
+            None => Err("unexpected end of formula".to_string()),
+        }
+    }
+
+    fn skip_whitespace(characters: &mut Peekable<Chars>) {
+        while characters
+            .next_if(|character| character.is_whitespace())
+            .is_some()
+        {}
+    }
+}
+
+impl FromStr for TimeFormula {
+    type Err = String;
+
+    fn from_str(formula: &str) -> Result<Self, Self::Err> {
+        let mut characters: Peekable<Chars> = formula.chars().peekable();
+        let parsed_formula: Result<TimeFormula, String> = TimeFormula::parse_sum(&mut characters)
+            .and_then(|parsed_formula| {
+                TimeFormula::skip_whitespace(&mut characters);
+                match characters.next() {
+                    None => Ok(parsed_formula),
+                    Some(character) => Err(format!("unexpected \"{character}\"")),
+                }
+            });
+
+        parsed_formula
+            .map_err(|formula_error| format!("Invalid time formula \"{formula}\": {formula_error}"))
+    }
+}
+
+/// Preset controlling the decimal and digit grouping separators used for the numeric header fields that have a fractional
+/// part. Integer fields, such as the time and layer count, are always written as plain digits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NumberFormat {
+    /// Decimal point, no digit grouping, e.g. "1234.56"
+    #[default]
+    Us,
+    /// Decimal comma, digits grouped in thousands with a point, e.g. "1.234,56"
+    Eu,
+}
+
+impl NumberFormat {
+    /// Separator placed between the integer and fractional parts of a number
+    pub fn decimal_separator(&self) -> char {
+        match self {
+            NumberFormat::Us => '.',
+            NumberFormat::Eu => ',',
+        }
+    }
+
+    /// Separator placed between each group of three integer digits, if any
+    pub fn grouping_separator(&self) -> Option<char> {
+        match self {
+            NumberFormat::Us => None,
+            NumberFormat::Eu => Some('.'),
+        }
+    }
+
+    /// Reformat a number, as produced by Rust's own formatting, using this preset's separators
+    pub fn format_number(&self, number: &str) -> String {
+        let (integer_part, fractional_part) = match number.split_once('.') {
+            Some((integer_part, fractional_part)) => (integer_part, Some(fractional_part)),
+            None => (number, None),
+        };
+        let (sign, digits) = match integer_part.strip_prefix('-') {
+            Some(digits) => ("-", digits),
+            None => ("", integer_part),
+        };
+
+        let mut formatted_number: String = sign.to_string();
+        for (index, digit) in digits.chars().enumerate() {
+            if index > 0 && (digits.len() - index) % 3 == 0 {
+                if let Some(grouping_separator) = self.grouping_separator() {
+                    formatted_number.push(grouping_separator);
+                }
+            }
+            formatted_number.push(digit);
+        }
+
+        if let Some(fractional_part) = fractional_part {
+            formatted_number.push(self.decimal_separator());
+            formatted_number.push_str(fractional_part);
+        }
+
+        formatted_number
+    }
+}
+
+impl FromStr for NumberFormat {
+    type Err = String;
+
+    fn from_str(preset: &str) -> Result<Self, Self::Err> {
+        match preset {
+            "us" => Ok(NumberFormat::Us),
+            "eu" => Ok(NumberFormat::Eu),
+            _ => Err(format!(
+                "Unknown number format \"{preset}\", expected \"us\" or \"eu\""
+            )),
+        }
+    }
+}
+
+/// Settings controlling how the Ankermake header is generated, as given on the command line.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Unit used for the filament used header field
+    pub filament_unit: FilamentUnit,
+    /// Separators used for the numeric header fields that have a fractional part
+    pub number_format: NumberFormat,
+    /// Maximum number of bytes read from a single line. A file with a longer line is left unconverted, or with `lenient`
+    /// the rest of the line is discarded.
+    pub max_line_length: usize,
+    /// Print the extracted fields as JSON lines on stdout. The files are only converted as well if an output path is given.
+    pub inspect: bool,
+    /// Print a table of stats for every file on stdout, without writing anything, not even sidecar files
+    pub report_only: bool,
+    /// Print what was found in each file on stdout, as it is converted
+    pub verbose: bool,
+    /// Only extract what can be read straight from Prusaslicer's comments, skipping every pass that follows the moves or
+    /// decodes the thumbnails. The fastest way to convert a file, but the header has no bounds or thumbnails, and the
+    /// report has no colors or feed rates.
+    pub fast: bool,
+    /// Report whether each of [`REQUIRED_HEADER_KEYS`] would be in the header, without writing anything. A file missing
+    /// any of them fails the check.
+    pub check: bool,
+    /// Skip estimates that can't be parsed, and truncate lines longer than `max_line_length`, rather than leaving the whole
+    /// file unconverted
+    pub lenient: bool,
+    /// Accept files made up of only comments, such as header-only test files, without warning
+    pub allow_empty_body: bool,
+    /// Number of files to process concurrently. Defaults to the number of cores available.
+    pub jobs: usize,
+    /// Search the subdirectories of any directory given for gcode files too, rather than only the directory itself
+    pub recursive: bool,
+    /// Write a CSV summary of every processed file to this path
+    pub csv: Option<String>,
+    /// Group the header fields between info block delimiters rather than leaving them flat
+    pub info_block: bool,
+    /// When the converted file is written back over the original
+    pub overwrite_policy: OverwritePolicy,
+    /// Write the converted file to this path rather than over the original. Only allowed with a single input file.
+    pub output: Option<String>,
+    /// Lines of gcode inserted between the header and the Prusaslicer gcode, e.g. a leveling or prime sequence
+    pub start_gcode: Vec<String>,
+    /// Extra extractors run over every line after the built-in extraction, for extending the header without forking
+    pub extractors: Vec<Arc<dyn FieldExtractor>>,
+    /// Seconds added to the estimated printing time in the header, to calibrate it against the real print time. May be
+    /// negative, the time never goes below zero.
+    pub time_offset: i64,
+    /// Write just the header lines to a sidecar file next to each gcode file, leaving the gcode itself untouched
+    pub header_sidecar: bool,
+    /// Formula applied to the estimated printing time in the header, before the time offset
+    pub time_formula: Option<TimeFormula>,
+    /// Values for header fields given on the command line, by their Ankermake key, used in place of the extracted ones
+    pub header_overrides: Vec<(&'static str, String)>,
+    /// Write how every line was classified to a trace file next to each gcode file, for debugging
+    pub trace_gcode: bool,
+    /// Leave Prusaslicer's config block out of the converted file. The header is still generated from it first.
+    pub strip_prusa_config: bool,
+    /// Add the weight of filament used to the header. When Prusaslicer didn't record it, it's estimated from the length
+    /// used along with the filament's diameter and density, or their usual values for the filament type.
+    pub filament_weight: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            filament_unit: FilamentUnit::default(),
+            number_format: NumberFormat::default(),
+            max_line_length: DEFAULT_MAX_LINE_LENGTH,
+            inspect: false,
+            report_only: false,
+            check: false,
+            verbose: false,
+            fast: false,
+            lenient: false,
+            allow_empty_body: false,
+            jobs: thread::available_parallelism().map_or(1, usize::from),
+            recursive: false,
+            csv: None,
+            info_block: false,
+            overwrite_policy: OverwritePolicy::default(),
+            output: None,
+            start_gcode: Vec::new(),
+            extractors: Vec::new(),
+            time_offset: 0,
+            header_sidecar: false,
+            time_formula: None,
+            header_overrides: Vec::new(),
+            strip_prusa_config: false,
+            trace_gcode: false,
+            filament_weight: false,
+        }
+    }
+}
+
+/// Classification of a single line of the file, worked out once per line so that each pass over the file can match on it
+/// rather than repeating its own prefix checks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LineKind<'a> {
+    /// A "; key = value" comment, holding the text after the semicolon and any whitespace around it
+    Metadata(&'a str),
+    /// A gcode instruction, trimmed of surrounding whitespace
+    Instruction(&'a str),
+    /// Any other comment, holding the whole line
+    Comment(&'a str),
+    /// An empty or whitespace only line
+    Blank,
+}
+
+impl<'a> LineKind<'a> {
+    /// Work out what kind of line this is
+    pub fn classify(line: &'a str) -> Self {
+        let trimmed_line: &str = line.trim();
+        // Prusaslicer metadata is written as "; key = value", but hand edited files may have other spacing around the
+        // semicolon, so strip it along with any whitespace rather than slicing off a fixed number of bytes
+        let attribute: Option<&str> = trimmed_line
+            .strip_prefix(';')
+            .map(str::trim_start)
+            .filter(|attribute| attribute.len() > 1 && attribute.contains('='));
+
+        if trimmed_line.is_empty() {
+            LineKind::Blank
+        } else if !trimmed_line.starts_with(';') {
+            LineKind::Instruction(trimmed_line)
+        } else if let Some(attribute) = attribute {
+            LineKind::Metadata(attribute)
+        } else {
+            LineKind::Comment(line)
+        }
+    }
+}
+
+/// Describe how every line was classified, one line per line of the file, numbered from 1
+pub fn trace_lines(line_kinds: &[LineKind]) -> String {
+    line_kinds
+        .iter()
+        .enumerate()
+        .map(|(index, line_kind)| format!("{}\t{line_kind:?}", index + 1))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Classify every line of the file
+pub fn classify_lines(lines: &[String]) -> Vec<LineKind<'_>> {
+    lines.iter().map(|line| LineKind::classify(line)).collect()
+}
+
+/// Selection of fields that we're interested in reformatting for the Ankermake M5 to understand.
+#[derive(Debug)]
+pub enum InterestingFields {
+    /// Time taken to print, represented as seconds
+    Time(u64),
+    /// Amount of filament used during printing, in um x10(0.01 mm)
+    FilamentUsed(u64),
+    /// Weight of filament used during printing, in hundredths of a gram, along with whether it was estimated from the
+    /// length used rather than taken from Prusaslicer
+    FilamentWeight(u64, bool),
+    /// gcode flavour. Marlin, unless Prusaslicer reports a flavour with another Ankermake equivalent
+    Flavour(String),
+    /// Version of this post-processor, for tracing which version touched a file
+    PostProcessor(&'static str),
+    /// Bed temperature after the first layer, in degrees Celsius, for the first extruder, along with whether it is the
+    /// default for the filament type rather than from the config
+    BedTemperature(u64, bool),
+    /// Hotend temperature after the first layer, in degrees Celsius, for the first extruder, along with whether it is the
+    /// default for the filament type rather than from the config
+    HotendTemperature(u64, bool),
+    /// Number of layers in the print
+    LayerCount(u64),
+    /// One of the bounds of the print, in millimeters, along with the header key for it, e.g. [`ANKERMAKE_MIN_X`]
+    Bound(&'static str, f64),
+    /// A preview image of the print, along with the header key for it, e.g. [`ANKERMAKE_LARGE_THUMBNAIL`]
+    Thumbnail(&'static str, Thumbnail),
+    /// Any other header field, written as ";key:value". Lets a [`FieldExtractor`] add keys this post-processor doesn't
+    /// know about.
+    Custom {
+        /// The header key
+        key: &'static str,
+        /// The value, written as it is
+        value: String,
+    },
+}
+
+impl InterestingFields {
+    /// The Ankermake header key this field is written under
+    pub fn header_key(&self) -> &'static str {
+        use InterestingFields::*;
+        match self {
+            Time(_) => ANKERMAKE_PRINTING_TIME,
+            FilamentUsed(_) => ANKERMAKE_FILAMENT_USED_M,
+            FilamentWeight(..) => ANKERMAKE_FILAMENT_USED_G,
+            Flavour(_) => ANKERMAKE_FLAVOUR,
+            PostProcessor(_) => ANKERMAKE_POST_PROCESSOR,
+            BedTemperature(..) => ANKERMAKE_BED_TEMPERATURE,
+            HotendTemperature(..) => ANKERMAKE_HOTEND_TEMPERATURE,
+            LayerCount(_) => ANKERMAKE_LAYER_COUNT,
+            Bound(key, _) | Thumbnail(key, _) | Custom { key, .. } => key,
+        }
+    }
+
+    /// Format the field as an Ankermake header line, respecting the given options
+    pub fn to_header_line(&self, options: &Options) -> String {
+        use InterestingFields::*;
+        let number_format: NumberFormat = options.number_format;
+        match self {
+            // Integer fields are left alone by the number format, the firmware would read grouping separators as a
+            // decimal point
+            Time(seconds) => format!(
+                ";{}:{}",
+                ANKERMAKE_PRINTING_TIME,
+                options
+                    .time_formula
+                    .as_ref()
+                    .map_or(*seconds, |time_formula| time_formula.apply(*seconds))
+                    .saturating_add_signed(options.time_offset)
+            ),
+            FilamentUsed(length_umx10) => match options.filament_unit {
+                FilamentUnit::Meters => format!(
+                    ";{}: {}m",
+                    ANKERMAKE_FILAMENT_USED_M,
+                    number_format
+                        .format_number(&format!("{:.5}", (*length_umx10 as f64) / 100000.0))
+                ),
+                FilamentUnit::Millimeters => format!(
+                    ";{}: {}mm",
+                    ANKERMAKE_FILAMENT_USED_M,
+                    number_format.format_number(&format!("{:.2}", (*length_umx10 as f64) / 100.0))
+                ),
+            },
+            FilamentWeight(weight_cg, _) => format!(
+                ";{}:{}",
+                ANKERMAKE_FILAMENT_USED_G,
+                number_format.format_number(&format!("{:.2}", (*weight_cg as f64) / 100.0))
+            ),
+            Flavour(flavour) => format!(";{}:{}", ANKERMAKE_FLAVOUR, flavour),
+            PostProcessor(version) => format!(
+                ";{}:{} {}",
+                ANKERMAKE_POST_PROCESSOR, POST_PROCESSOR_NAME, version
+            ),
+            BedTemperature(celsius, _) => format!(";{}:{}", ANKERMAKE_BED_TEMPERATURE, celsius),
+            HotendTemperature(celsius, _) => {
+                format!(";{}:{}", ANKERMAKE_HOTEND_TEMPERATURE, celsius)
+            }
+            LayerCount(layers) => format!(";{}:{}", ANKERMAKE_LAYER_COUNT, layers),
+            Bound(key, millimeters) => format!(
+                ";{}:{}",
+                key,
+                number_format.format_number(&format!("{:.2}", millimeters))
+            ),
+            Thumbnail(key, thumbnail) => format!(";{}:{}", key, thumbnail.base64),
+            Custom { key, value } => format!(";{}:{}", key, value),
+        }
+    }
+
+    /// Format the field as a `"key":value` JSON object member, for the inspect output
+    pub fn to_json_member(&self) -> String {
+        use InterestingFields::*;
+        match self {
+            Time(seconds) => format!("\"time_seconds\":{}", seconds),
+            FilamentUsed(length_umx10) => {
+                format!("\"filament_used_mm\":{}", (*length_umx10 as f64) / 100.0)
+            }
+            FilamentWeight(weight_cg, estimated) => format!(
+                "\"filament_used_g\":{{\"grams\":{},\"estimated\":{}}}",
+                (*weight_cg as f64) / 100.0,
+                estimated
+            ),
+            Flavour(flavour) => format!("\"flavour\":{}", to_json_string(flavour)),
+            PostProcessor(version) => {
+                format!("\"post_processor_version\":{}", to_json_string(version))
+            }
+            BedTemperature(celsius, default) => format!(
+                "\"bed_temperature\":{{\"celsius\":{},\"default\":{}}}",
+                celsius, default
+            ),
+            HotendTemperature(celsius, default) => format!(
+                "\"hotend_temperature\":{{\"celsius\":{},\"default\":{}}}",
+                celsius, default
+            ),
+            LayerCount(layers) => format!("\"layer_count\":{}", layers),
+            Bound(key, millimeters) => format!("\"{}_mm\":{}", key.to_lowercase(), millimeters),
+            Thumbnail(key, thumbnail) => format!(
+                "\"{}\":{{\"width\":{},\"height\":{}}}",
+                key, thumbnail.width, thumbnail.height
+            ),
+            Custom { key, value } => format!("{}:{}", to_json_string(key), to_json_string(value)),
+        }
+    }
+}
+
+/// A PNG preview image of the print, as embedded by Prusaslicer
+#[derive(Debug, Clone, PartialEq)]
+pub struct Thumbnail {
+    /// Width in pixels
+    pub width: u64,
+    /// Height in pixels
+    pub height: u64,
+    /// The PNG, base64 encoded
+    pub base64: String,
+}
+
+impl Thumbnail {
+    /// Collect every PNG thumbnail Prusaslicer embedded in the file, in the order they appear. Thumbnails in other
+    /// formats, or whose payload doesn't decode to a PNG, are left out.
+    pub fn from_lines(lines: &[LineKind]) -> Vec<Thumbnail> {
+        let mut thumbnails: Vec<Thumbnail> = Vec::new();
+        let mut current: Option<Thumbnail> = None;
+
+        lines.iter().filter_map(comment_text).for_each(|text| {
+            if let Some(description) = text.strip_prefix(PRUSA_THUMBNAIL_BEGIN) {
+                current = description
+                    .split_whitespace()
+                    .next()
+                    .and_then(|size| size.split_once('x'))
+                    .and_then(|(width, height)| {
+                        Some(Thumbnail {
+                            width: width.parse().ok()?,
+                            height: height.parse().ok()?,
+                            base64: String::new(),
+                        })
+                    });
+            } else if text == PRUSA_THUMBNAIL_END {
+                thumbnails.extend(current.take().filter(|thumbnail| {
+                    decode_base64(&thumbnail.base64).is_some_and(|png| png.starts_with(PNG_MAGIC))
+                }));
+            } else if let Some(thumbnail) = &mut current {
+                thumbnail.base64.push_str(text);
+            }
+        });
+
+        thumbnails
+    }
+
+    /// Number of pixels in the image
+    pub fn area(&self) -> u64 {
+        self.width * self.height
+    }
+}
+
+/// The text of a comment line after its semicolon and any whitespace around it, whether or not it looks like metadata
+pub fn comment_text<'a>(line: &LineKind<'a>) -> Option<&'a str> {
+    match line {
+        LineKind::Metadata(attribute) => Some(attribute.trim_end()),
+        LineKind::Comment(comment) => comment.trim().strip_prefix(';').map(str::trim_start),
+        _ => None,
+    }
+}
+
+/// Decode standard base64, with or without padding, returning `None` if there are any characters outside its alphabet
+pub fn decode_base64(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded: Vec<u8> = Vec::with_capacity(encoded.len() / 4 * 3);
+    let mut buffer: u32 = 0;
+    let mut buffered_bits: u32 = 0;
+
+    for byte in encoded.trim_end_matches('=').bytes() {
+        let value: u8 = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        buffer = (buffer << 6 | value as u32) & 0xFFFF;
+        buffered_bits += 6;
+        if buffered_bits >= 8 {
+            buffered_bits -= 8;
+            decoded.push((buffer >> buffered_bits) as u8);
+        }
+    }
+
+    Some(decoded)
+}
+
+/// Extracts a header field from a single line. Extractors registered in [`Options::extractors`] are run over every line of
+/// the file, and every field they return is added to the header after the built-in fields.
+pub trait FieldExtractor: std::fmt::Debug + Send + Sync {
+    /// Try to extract a field from the line, returning `None` if the line isn't one this extractor handles
+    fn try_extract(&self, line: &LineKind) -> Option<InterestingFields>;
+}
+
+/// Output produced while processing a single file. It is buffered rather than printed straight away so that the output
+/// of files being processed concurrently doesn't interleave.
+#[derive(Debug, Default)]
+pub struct FileLog {
+    /// Lines destined for stdout, such as the inspect output
+    pub stdout: Vec<String>,
+    /// Warnings and errors, which the binary prints to stderr
+    pub diagnostics: Vec<Diagnostic>,
+    /// What was extracted from the file, if it was needed for the inspect output or a batch-wide summary
+    pub summary: Option<FileSummary>,
+}
+
+impl FileLog {
+    /// Whether anything went wrong that stopped the file from being converted
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics.iter().any(Diagnostic::is_error)
+    }
+}
+
+/// A problem found while processing a file. Warnings are advisory and the file is still converted, errors mean the file
+/// was left as it is.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Diagnostic {
+    /// A line was longer than the maximum line length, and was truncated as `--lenient` was given
+    LineTruncated {
+        /// Line number, counting from 1
+        line: usize,
+        /// The maximum line length, in bytes
+        max_line_length: usize,
+    },
+    /// The file has no gcode instructions, only comments
+    NoGcodeBody,
+    /// The file is missing the Prusaslicer estimates or the end of its config, so was likely cut short
+    LooksTruncated,
+    /// No value could be found for a header field, given by its Ankermake key
+    MissingField(&'static str),
+    /// The nozzle diameter is at least as large as the filament diameter, so the two were likely swapped
+    SwappedDiameters {
+        /// Nozzle diameter in millimeters
+        nozzle_diameter: f64,
+        /// Filament diameter in millimeters
+        filament_diameter: f64,
+    },
+    /// The nozzle diameter, in millimeters, is too small to be a real nozzle
+    ImplausibleNozzleDiameter(f64),
+    /// The file was generated by a Prusaslicer version newer than any this post-processor has been checked against
+    UntestedSlicerVersion(String),
+    /// The filament extruded by the moves in the file is well off Prusaslicer's estimate, so the file was likely edited
+    FilamentMismatch {
+        /// Prusaslicer's estimate, in millimeters
+        estimated_mm: f64,
+        /// Total extruded by the moves, in millimeters
+        extruded_mm: f64,
+    },
+    /// The config doesn't record the print temperatures, so the defaults for its filament type were used in the header
+    DefaultTemperatures,
+    /// A metadata line the header depends on couldn't be parsed, and was skipped as `--lenient` was given
+    SkippedLine {
+        /// Line number, counting from 1
+        line: usize,
+        /// What was wrong with the line
+        error: ParsingError,
+    },
+    /// A metadata line the header depends on couldn't be parsed, so the file was left as it is
+    Parsing {
+        /// Path of the file
+        path: String,
+        /// What was wrong with the line
+        error: ParsingError,
+    },
+    /// The file is binary gcode, which can't be converted
+    BinaryGcode(String),
+    /// The file is compressed in a way this build can't read, as the feature for it wasn't enabled
+    CompressedGcode {
+        /// Path of the file
+        path: String,
+        /// How the file is compressed
+        compression: Compression,
+    },
+    /// The file was found by searching a directory, but already has a header from this post-processor, so was skipped
+    AlreadyConverted(String),
+    /// The header generated for the file would be missing some of the required keys, so the file failed `--check`
+    IncompleteHeader {
+        /// Path of the file
+        path: String,
+        /// The required keys that would be missing
+        missing: Vec<&'static str>,
+    },
+    /// Opening, reading or writing the file failed
+    Io {
+        /// What was being done to the file, e.g. "open"
+        action: &'static str,
+        /// Path of the file
+        path: String,
+        /// The error reported by the OS
+        error: String,
+    },
+}
+
+impl Diagnostic {
+    /// Whether the diagnostic is an error that stopped the file from being converted, rather than a warning
+    pub fn is_error(&self) -> bool {
+        matches!(
+            self,
+            Diagnostic::Parsing { .. }
+                | Diagnostic::BinaryGcode(_)
+                | Diagnostic::CompressedGcode { .. }
+                | Diagnostic::IncompleteHeader { .. }
+                | Diagnostic::Io { .. }
+        )
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use Diagnostic::*;
+        if !self.is_error() {
+            write!(formatter, "Warning: ")?;
+        }
+
+        match self {
+            LineTruncated { line, max_line_length } => write!(
+                formatter,
+                "line {line} is longer than {max_line_length} bytes and has been truncated"
+            ),
+            NoGcodeBody => write!(formatter, "file contains no gcode instructions, only comments"),
+            LooksTruncated => write!(
+                formatter,
+                "file looks truncated, the Prusaslicer estimates or end of its config are missing"
+            ),
+            MissingField(key) => write!(formatter, "no value found for the {key} header field"),
+            SwappedDiameters { nozzle_diameter, filament_diameter } => write!(
+                formatter,
+                "nozzle diameter {nozzle_diameter}mm is not smaller than the filament diameter {filament_diameter}mm, the profile values may be swapped"
+            ),
+            ImplausibleNozzleDiameter(nozzle_diameter) => write!(
+                formatter,
+                "nozzle diameter {nozzle_diameter}mm is implausibly small, check the printer profile"
+            ),
+            UntestedSlicerVersion(version) => write!(
+                formatter,
+                "file was generated by Prusaslicer {version}, which is newer than this post-processor has been tested with, the header may be incomplete"
+            ),
+            FilamentMismatch { estimated_mm, extruded_mm } => write!(
+                formatter,
+                "the moves in the file extrude {extruded_mm:.2}mm of filament, but Prusaslicer estimated {estimated_mm:.2}mm, the filament used in the header may be wrong"
+            ),
+            DefaultTemperatures => write!(
+                formatter,
+                "the config has no print temperatures, the usual ones for its filament type were used"
+            ),
+            SkippedLine { line, error } => {
+                write!(formatter, "skipped line {line} as it couldn't be parsed, {error}")
+            }
+            Parsing { path, error } => {
+                write!(formatter, "Failed to parse file at \"{path}\": {error}")
+            }
+            BinaryGcode(path) => write!(
+                formatter,
+                "Skipping \"{path}\": it is binary gcode, which the M5 can't read. Export plain text gcode from Prusaslicer instead."
+            ),
+            AlreadyConverted(path) => {
+                write!(formatter, "skipping \"{path}\", it has already been converted")
+            }
+            IncompleteHeader { path, missing } => write!(
+                formatter,
+                "Check failed for \"{path}\": the header would be missing {}",
+                missing.join(", ")
+            ),
+            CompressedGcode { path, compression } => write!(
+                formatter,
+                "Skipping \"{path}\": it is {compression} compressed, which this build can't read. Decompress it first, or build with the \"{}\" feature.",
+                compression.feature()
+            ),
+            Io { action, path, error } => {
+                write!(formatter, "Failed to {action} file at \"{path}\": {error}")
+            }
+        }
+    }
+}
+
+/// Where in the print a color change happens
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColorChange {
+    /// Layer the color change happens on, counting from 1. Zero if it happens before the first layer.
+    pub layer: u64,
+    /// Height of that layer in millimeters, if Prusaslicer recorded it
+    pub z: Option<f64>,
+}
+
+/// Details of the print that are reported by the inspect mode, but aren't written into the header.
+#[derive(Debug, Default)]
+pub struct PrintReport {
+    /// Number of toolchanges in a multi-material print, zero for single material prints
+    pub total_toolchanges: u64,
+    /// Whether the external perimeter is printed before the inner perimeters, if the config records it
+    pub external_perimeters_first: Option<bool>,
+    /// Whether infill is printed before the perimeters, if the config records it
+    pub infill_first: Option<bool>,
+    /// Every color change in the print, in print order
+    pub color_changes: Vec<ColorChange>,
+    /// Millimeters of filament extruded in each color, starting with the color the print starts in. There is one more
+    /// entry than there are color changes, or none at all with [`Options::fast`].
+    pub filament_per_color_mm: Vec<f64>,
+    /// Height of the finished print in millimeters, from the config if it records it, otherwise the highest layer
+    pub print_height: Option<f64>,
+    /// Every object on the bed, if Prusaslicer recorded a well formed objects info block
+    pub objects: Option<Vec<ObjectInfo>>,
+    /// Number of layers, counted from Prusaslicer's layer change comments
+    pub layer_count: u64,
+    /// Feed rates of the moves, if any moves had a feed rate set
+    pub feed_rate: Option<FeedRateStats>,
+}
+
+/// An object on the bed, as described by Prusaslicer's objects info block
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectInfo {
+    /// Name of the object, including the instance suffix Prusaslicer adds, e.g. "cube.stl_id_0_copy_0"
+    pub name: String,
+    /// Outline of the object's footprint on the bed, as X and Y coordinates in millimeters
+    pub polygon: Vec<[f64; 2]>,
+}
+
+impl ObjectInfo {
+    /// Parse the objects from the JSON value of the objects info attribute, returning `None` if it is malformed
+    pub fn from_objects_info(json: &str) -> Option<Vec<ObjectInfo>> {
+        JsonValue::parse(json)?
+            .get("objects")?
+            .as_array()?
+            .iter()
+            .map(|object| {
+                let polygon: Vec<[f64; 2]> = object
+                    .get("polygon")?
+                    .as_array()?
+                    .iter()
+                    .map(|point| match point.as_array()?.as_slice() {
+                        [x, y] => Some([x.as_f64()?, y.as_f64()?]),
+                        _ => None,
+                    })
+                    .collect::<Option<_>>()?;
+
+                Some(ObjectInfo {
+                    name: object.get("name")?.as_str()?.to_string(),
+                    polygon,
+                })
+            })
+            .collect()
+    }
+}
+
+/// The command of a gcode instruction, e.g. "G1" for "G1 X10 E0.5"
+pub fn gcode_command(instruction: &str) -> &str {
+    instruction
+        .split(';')
+        .next()
+        .and_then(|instruction| instruction.split_whitespace().next())
+        .unwrap_or_default()
+}
+
+/// Look up the value of one of a gcode instruction's parameters, e.g. 0.5 for 'E' in "G1 X10 E0.5 ; comment"
+pub fn gcode_parameter(instruction: &str, letter: char) -> Option<f64> {
+    // Drop any trailing comment before looking at the parameters
+    instruction
+        .split(';')
+        .next()?
+        .split_whitespace()
+        .skip(1)
+        .find_map(|word| word.strip_prefix(letter))?
+        .parse()
+        .ok()
+}
+
+/// The spread of feed rates used by the moves in the print, in millimeters per minute
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeedRateStats {
+    /// Slowest feed rate set for a move
+    pub min: f64,
+    /// Fastest feed rate set for a move
+    pub max: f64,
+    /// Feed rate averaged over every move, rather than over the distance moved
+    pub average: f64,
+}
+
+/// Follows the extruder position through the gcode, to work out how much filament each move extrudes.
+#[derive(Debug, Default)]
+pub struct ExtrusionTracker {
+    /// Whether E values are relative to the last position, rather than absolute
+    pub relative: bool,
+    /// The extruder position, in millimeters, as of the last instruction
+    pub position: f64,
+}
+
+impl ExtrusionTracker {
+    /// Follow a single instruction, returning the millimeters of filament it extrudes. Retractions come out negative so
+    /// that they cancel out the matching unretraction.
+    pub fn extruded_by(&mut self, instruction: &str) -> f64 {
+        let e_value: Option<f64> = gcode_parameter(instruction, 'E');
+
+        match (gcode_command(instruction), e_value) {
+            (GCODE_ABSOLUTE_EXTRUSION, _) => self.relative = false,
+            (GCODE_RELATIVE_EXTRUSION, _) => self.relative = true,
+            (GCODE_SET_POSITION, Some(e_value)) => self.position = e_value,
+            ("G0" | "G1", Some(e_value)) => {
+                let extruded: f64 = if self.relative {
+                    e_value
+                } else {
+                    e_value - self.position
+                };
+                self.position += extruded;
+                return extruded;
+            }
+            _ => {}
+        }
+
+        0.0
+    }
+}
+
+/// Follows the position of the nozzle through the gcode, in millimeters.
+#[derive(Debug, Default)]
+pub struct PositionTracker {
+    /// Whether X, Y and Z values are relative to the last position, rather than absolute
+    pub relative: bool,
+    /// The X, Y and Z position as of the last instruction
+    pub position: [f64; 3],
+}
+
+impl PositionTracker {
+    /// Follow a single instruction, returning the position it moves to if it is a move. Homing is taken to move the homed
+    /// axes to zero.
+    pub fn moved_to(&mut self, instruction: &str) -> Option<[f64; 3]> {
+        let values: [Option<f64>; 3] =
+            ['X', 'Y', 'Z'].map(|letter| gcode_parameter(instruction, letter));
+
+        match gcode_command(instruction) {
+            GCODE_ABSOLUTE_POSITIONING => self.relative = false,
+            GCODE_RELATIVE_POSITIONING => self.relative = true,
+            GCODE_SET_POSITION => self.set_axes(values, |_, value| value),
+            GCODE_HOME => {
+                // The axes to home don't need a value, e.g. "G28 X", so look for their letters on their own
+                let named: [bool; 3] = ['X', 'Y', 'Z'].map(|letter| {
+                    instruction
+                        .split(';')
+                        .next()
+                        .unwrap_or_default()
+                        .split_whitespace()
+                        .skip(1)
+                        .any(|word| word.starts_with(letter))
+                });
+                let home_all: bool = !named.contains(&true);
+                (0..3)
+                    .filter(|axis| home_all || named[*axis])
+                    .for_each(|axis| self.position[axis] = 0.0);
+            }
+            "G0" | "G1" => {
+                let relative: bool = self.relative;
+                self.set_axes(
+                    values,
+                    |old_value, value| {
+                        if relative {
+                            old_value + value
+                        } else {
+                            value
+                        }
+                    },
+                );
+                return Some(self.position);
+            }
+            _ => {}
+        }
+
+        None
+    }
+
+    /// Update every axis that has a value, from its old value and the new one
+    fn set_axes(&mut self, values: [Option<f64>; 3], update: impl Fn(f64, f64) -> f64) {
+        self.position
+            .iter_mut()
+            .zip(values)
+            .for_each(|(position, value)| {
+                if let Some(value) = value {
+                    *position = update(*position, value);
+                }
+            });
+    }
+}
+
+/// The smallest box, aligned with the axes, containing everything the print extrudes
+#[derive(Debug, Clone, PartialEq)]
+pub struct BoundingBox {
+    /// Lowest X, Y and Z, in millimeters
+    pub min: [f64; 3],
+    /// Highest X, Y and Z, in millimeters
+    pub max: [f64; 3],
+}
+
+impl BoundingBox {
+    /// Find the bounds of the print from its extruding moves, including where each one starts. Travel moves are left out,
+    /// as they can go well outside the print, e.g. to park the nozzle or wipe it. Returns `None` if nothing is extruded.
+    pub fn from_lines(lines: &[LineKind]) -> Option<Self> {
+        let mut extrusion: ExtrusionTracker = ExtrusionTracker::default();
+        let mut position: PositionTracker = PositionTracker::default();
+        let mut bounding_box: Option<BoundingBox> = None;
+
+        lines.iter().for_each(|line| {
+            if let LineKind::Instruction(instruction) = line {
+                let start: [f64; 3] = position.position;
+                let end: Option<[f64; 3]> = position.moved_to(instruction);
+                let extruded: f64 = extrusion.extruded_by(instruction);
+                if let Some(end) = end.filter(|_| extruded > 0.0) {
+                    for point in [start, end] {
+                        let bounding_box: &mut BoundingBox =
+                            bounding_box.get_or_insert(BoundingBox {
+                                min: point,
+                                max: point,
+                            });
+                        for (axis, value) in point.into_iter().enumerate() {
+                            bounding_box.min[axis] = bounding_box.min[axis].min(value);
+                            bounding_box.max[axis] = bounding_box.max[axis].max(value);
+                        }
+                    }
+                }
+            }
+        });
+
+        bounding_box
+    }
+
+    /// The header fields for each of the bounds. The top of the print is taken from `max_layer_z`, the height Prusaslicer
+    /// records in its config, if given, as that is exact rather than scanned from the moves.
+    pub fn to_fields(&self, max_layer_z: Option<f64>) -> [InterestingFields; 6] {
+        use InterestingFields::Bound;
+        [
+            Bound(ANKERMAKE_MIN_X, self.min[0]),
+            Bound(ANKERMAKE_MAX_X, self.max[0]),
+            Bound(ANKERMAKE_MIN_Y, self.min[1]),
+            Bound(ANKERMAKE_MAX_Y, self.max[1]),
+            Bound(ANKERMAKE_MIN_Z, self.min[2]),
+            Bound(ANKERMAKE_MAX_Z, max_layer_z.unwrap_or(self.max[2])),
+        ]
+    }
+}
+
+impl PrintReport {
+    /// Gather the report from the lines of the file, leaving anything that can't be found at its default. With
+    /// `options.fast` the moves aren't followed, so there are no colors or feed rates.
+    pub fn from_lines(lines: &[LineKind], options: &Options) -> Self {
+        let mut report: PrintReport = PrintReport {
+            filament_per_color_mm: if options.fast { Vec::new() } else { vec![0.0] },
+            ..PrintReport::default()
+        };
+        let mut extrusion: ExtrusionTracker = ExtrusionTracker::default();
+        let mut layer: u64 = 0;
+        let mut z: Option<f64> = None;
+        // Prusaslicer's color change comment is normally followed by the M600 itself, which shouldn't be counted twice
+        let mut color_change_pending: bool = false;
+        let mut max_layer_z: Option<f64> = None;
+        // The feed rate carries over from move to move until it is set again
+        let mut feed_rate: Option<f64> = None;
+        let mut feed_rate_total: f64 = 0.0;
+        let mut feed_rate_moves: u64 = 0;
+        let mut highest_layer_z: Option<f64> = None;
+
+        lines.iter().for_each(|line| match line {
+            LineKind::Metadata(attribute) => {
+                if has_attribute_key(attribute, PRUSA_TOTAL_TOOLCHANGES) {
+                    if let Some(toolchanges) = parse_attribute_value(attribute) {
+                        report.total_toolchanges = toolchanges;
+                    }
+                } else if has_attribute_key(attribute, PRUSA_EXTERNAL_PERIMETERS_FIRST) {
+                    report.external_perimeters_first = parse_prusa_bool(attribute);
+                } else if has_attribute_key(attribute, PRUSA_INFILL_FIRST) {
+                    report.infill_first = parse_prusa_bool(attribute);
+                } else if has_attribute_key(attribute, PRUSA_OBJECTS_INFO) {
+                    report.objects = attribute
+                        .split_once('=')
+                        .and_then(|(_, json)| ObjectInfo::from_objects_info(json));
+                } else if has_attribute_key(attribute, PRUSA_MAX_LAYER_Z) {
+                    max_layer_z = parse_attribute_value(attribute);
+                }
+            }
+            LineKind::Comment(comment) => {
+                let comment: &str = comment.trim();
+                if comment == PRUSA_LAYER_CHANGE {
+                    layer += 1;
+                    z = None;
+                } else if let Some(height) = comment.strip_prefix(PRUSA_LAYER_Z) {
+                    z = height.trim().parse().ok();
+                    if let Some(z) = z {
+                        highest_layer_z = Some(highest_layer_z.map_or(z, |highest| highest.max(z)));
+                    }
+                } else if comment.starts_with(PRUSA_COLOR_CHANGE) && !options.fast {
+                    report.color_changes.push(ColorChange { layer, z });
+                    report.filament_per_color_mm.push(0.0);
+                    color_change_pending = true;
+                }
+            }
+            LineKind::Instruction(instruction) if !options.fast => {
+                let is_filament_change: bool = gcode_command(instruction) == GCODE_FILAMENT_CHANGE;
+                if is_filament_change && !color_change_pending {
+                    report.color_changes.push(ColorChange { layer, z });
+                    report.filament_per_color_mm.push(0.0);
+                }
+                color_change_pending = false;
+
+                if let Some(current_color) = report.filament_per_color_mm.last_mut() {
+                    *current_color += extrusion.extruded_by(instruction);
+                }
+
+                if matches!(gcode_command(instruction), "G0" | "G1") {
+                    feed_rate = gcode_parameter(instruction, 'F').or(feed_rate);
+                    if let Some(feed_rate) = feed_rate {
+                        feed_rate_total += feed_rate;
+                        feed_rate_moves += 1;
+                        report.feed_rate = Some(match report.feed_rate.take() {
+                            Some(stats) => FeedRateStats {
+                                min: stats.min.min(feed_rate),
+                                max: stats.max.max(feed_rate),
+                                average: feed_rate_total / feed_rate_moves as f64,
+                            },
+                            None => FeedRateStats {
+                                min: feed_rate,
+                                max: feed_rate,
+                                average: feed_rate,
+                            },
+                        });
+                    }
+                }
+            }
+            LineKind::Instruction(_) | LineKind::Blank => {}
+        });
+
+        // The config value is exact, scanning the layers is only a fallback for versions that don't record it
+        report.print_height = max_layer_z.or(highest_layer_z);
+        report.layer_count = layer;
+        report
+    }
+
+    /// Format the report as `"key":value` JSON object members, for the inspect output
+    pub fn to_json_members(&self) -> Vec<String> {
+        let mut members: Vec<String> =
+            vec![format!("\"total_toolchanges\":{}", self.total_toolchanges)];
+        if let Some(external_perimeters_first) = self.external_perimeters_first {
+            members.push(format!(
+                "\"external_perimeters_first\":{external_perimeters_first}"
+            ));
+        }
+        if let Some(infill_first) = self.infill_first {
+            members.push(format!("\"infill_first\":{infill_first}"));
+        }
+        // The layer count is already given with the header fields, when there are any layers
+        if let Some(feed_rate) = &self.feed_rate {
+            members.push(format!(
+                "\"feed_rate_mm_per_min\":{{\"min\":{},\"max\":{},\"average\":{:.2}}}",
+                feed_rate.min, feed_rate.max, feed_rate.average
+            ));
+        }
+        if let Some(print_height) = self.print_height {
+            members.push(format!("\"print_height_mm\":{print_height}"));
+        }
+        if let Some(objects) = &self.objects {
+            let objects: Vec<String> = objects
+                .iter()
+                .map(|object| {
+                    format!(
+                        "{{\"name\":{},\"polygon_points\":{}}}",
+                        to_json_string(&object.name),
+                        object.polygon.len()
+                    )
+                })
+                .collect();
+            members.push(format!("\"objects\":[{}]", objects.join(",")));
+        }
+
+        let color_changes: Vec<String> = self
+            .color_changes
+            .iter()
+            .map(|color_change| match color_change.z {
+                Some(z) => format!("{{\"layer\":{},\"z\":{}}}", color_change.layer, z),
+                None => format!("{{\"layer\":{},\"z\":null}}", color_change.layer),
+            })
+            .collect();
+        members.push(format!(
+            "\"color_change_count\":{}",
+            self.color_changes.len()
+        ));
+        members.push(format!("\"color_changes\":[{}]", color_changes.join(",")));
+
+        let filament_per_color_mm: Vec<String> = self
+            .filament_per_color_mm
+            .iter()
+            .map(|length| format!("{length:.2}"))
+            .collect();
+        members.push(format!(
+            "\"filament_per_color_mm\":[{}]",
+            filament_per_color_mm.join(",")
+        ));
+
+        members
+    }
+}
+
+/// Check whether a metadata attribute is for the given Prusaslicer key. Keys are matched regardless of case, so that hand
+/// edited files still match, but the key must be followed by something other than more of a key name, so that e.g.
+/// "infill_first" doesn't match "infill_first_layer".
+pub fn has_attribute_key(attribute: &str, key: &str) -> bool {
+    let Some(attribute_key) = attribute.get(..key.len()) else {
+        return false;
+    };
+
+    attribute_key.eq_ignore_ascii_case(key)
+        && !attribute[key.len()..]
+            .starts_with(|character: char| character.is_alphanumeric() || character == '_')
+}
+
+/// Parse the value of a "key = value" attribute, e.g. 12.4 from "max_layer_z = 12.4"
+pub fn parse_attribute_value<T: FromStr>(attribute: &str) -> Option<T> {
+    attribute.split('=').nth(1)?.trim().parse().ok()
+}
+
+/// Parse a Prusaslicer boolean config attribute, written as "key = 0" or "key = 1"
+pub fn parse_prusa_bool(attribute: &str) -> Option<bool> {
+    match attribute.split('=').nth(1)?.trim() {
+        "0" => Some(false),
+        "1" => Some(true),
+        _ => None,
+    }
+}
+
+/// Every key this post-processor can write into the Ankermake header
+pub const ANKERMAKE_HEADER_KEYS: [&str; 16] = [
+    ANKERMAKE_PRINTING_TIME,
+    ANKERMAKE_FILAMENT_USED_M,
+    ANKERMAKE_FILAMENT_USED_G,
+    ANKERMAKE_FLAVOUR,
+    ANKERMAKE_POST_PROCESSOR,
+    ANKERMAKE_BED_TEMPERATURE,
+    ANKERMAKE_HOTEND_TEMPERATURE,
+    ANKERMAKE_LAYER_COUNT,
+    ANKERMAKE_MIN_X,
+    ANKERMAKE_MAX_X,
+    ANKERMAKE_MIN_Y,
+    ANKERMAKE_MAX_Y,
+    ANKERMAKE_MIN_Z,
+    ANKERMAKE_MAX_Z,
+    ANKERMAKE_LARGE_THUMBNAIL,
+    ANKERMAKE_SMALL_THUMBNAIL,
+];
+
+/// Format a number of seconds as hours, minutes and seconds, e.g. "1h 02m 03s"
+pub fn format_duration(seconds: u64) -> String {
+    format!(
+        "{}h {:02}m {:02}s",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// Keys the M5 needs in the header to estimate the print, which `--check` requires
+pub const REQUIRED_HEADER_KEYS: [&str; 3] = [
+    ANKERMAKE_PRINTING_TIME,
+    ANKERMAKE_FILAMENT_USED_M,
+    ANKERMAKE_FLAVOUR,
+];
+
+/// If the line is an Ankermake header annotation, e.g. ";TIME:3723" from an earlier run or an Ankermake slice, return its key.
+pub fn ankermake_header_key(line: &str) -> Option<&str> {
+    header_line_key(line).filter(|key| ANKERMAKE_HEADER_KEYS.contains(key))
+}
+
+/// If the line is written like a header field, e.g. ";TIME:3723", return its key, whether or not it's one of
+/// [`ANKERMAKE_HEADER_KEYS`]. Used to find fields from an earlier run, including any added by a [`FieldExtractor`].
+pub fn header_line_key(line: &str) -> Option<&str> {
+    let (key, _) = line.strip_prefix(';')?.split_once(':')?;
+    Some(key)
+}
+
+/// The version of this post-processor, as given in its package manifest
+pub fn version() -> &'static str {
+    env!("CARGO_PKG_VERSION")
+}
+
+/// Column names of the CSV summary, matching the order of [`FileSummary::to_csv_row`]
+pub const CSV_HEADER: &str = "path,time_seconds,filament_m,layers,flavour,total_toolchanges";
+
+/// Column names of the report-only stats table, matching the order of [`FileSummary::to_report_row`]
+pub const REPORT_COLUMNS: [&str; 10] = [
+    "time",
+    "filament_mm",
+    "filament_m",
+    "layers",
+    "bed_c",
+    "hotend_c",
+    "colors",
+    "toolchanges",
+    "features",
+    "path",
+];
+
+/// Lay out a row of the report-only stats table, right aligning every column but the features and the path, which come
+/// last as they have no predictable width
+pub fn format_report_row(columns: [&str; 10]) -> String {
+    let [time, filament_mm, filament_m, layers, bed, hotend, colors, toolchanges, features, path] =
+        columns;
+    format!(
+        "{time:>12} {filament_mm:>12} {filament_m:>11} {layers:>7} {bed:>6} {hotend:>8} {colors:>7} {toolchanges:>12}  \
+        {features:<28}  {path}"
+    )
+}
+
+/// Everything extracted from a single file, used for the inspect output and the batch-wide summaries.
+#[derive(Debug)]
+pub struct FileSummary {
+    /// Path of the file, as it was given on the command line
+    pub path: String,
+    /// The header fields extracted from the file
+    pub fields: Vec<InterestingFields>,
+    /// The details that are reported, but not written into the header
+    pub report: PrintReport,
+}
+
+impl FileSummary {
+    /// Summarise the file from its classified lines and the header fields already extracted from them
+    pub fn from_lines(
+        path: &str,
+        lines: &[LineKind],
+        fields: Vec<InterestingFields>,
+        options: &Options,
+    ) -> Self {
+        FileSummary {
+            path: path.to_string(),
+            fields,
+            report: PrintReport::from_lines(lines, options),
+        }
+    }
+
+    /// Describe what was found in the file on a single line, for the verbose output. Lists which header keys were found,
+    /// and which of the [`REQUIRED_HEADER_KEYS`] weren't.
+    pub fn to_verbose_line(&self) -> String {
+        let mut time: String = "-".to_string();
+        let mut filament_m: String = "-".to_string();
+        let mut flavour: &str = "-";
+        self.fields.iter().for_each(|field| match field {
+            InterestingFields::Time(seconds) => time = format_duration(*seconds),
+            InterestingFields::FilamentUsed(length_umx10) => {
+                filament_m = format!("{:.5}m", (*length_umx10 as f64) / 100000.0)
+            }
+            InterestingFields::Flavour(name) => flavour = name,
+            _ => {}
+        });
+
+        let found_keys: Vec<&'static str> = self
+            .fields
+            .iter()
+            .map(InterestingFields::header_key)
+            .collect();
+        let missing_keys: Vec<&'static str> = REQUIRED_HEADER_KEYS
+            .into_iter()
+            .filter(|key| !found_keys.contains(key))
+            .collect();
+        let list = |keys: &[&str]| -> String {
+            if keys.is_empty() {
+                "none".to_string()
+            } else {
+                keys.join(", ")
+            }
+        };
+
+        format!(
+            "{}: time {time}, filament {filament_m}, flavour {flavour}, found {}, missing {}",
+            self.path,
+            list(&found_keys),
+            list(&missing_keys)
+        )
+    }
+
+    /// Describe the summary as a single line JSON object, for the inspect output. Only the first field for each header key
+    /// is described, as an object can't repeat a key.
+    pub fn to_json(&self) -> String {
+        let mut members: Vec<String> = vec![format!("\"path\":{}", to_json_string(&self.path))];
+        let mut described_keys: Vec<&'static str> = Vec::new();
+        members.extend(
+            self.fields
+                .iter()
+                .filter(|field| {
+                    let is_new_key: bool = !described_keys.contains(&field.header_key());
+                    described_keys.push(field.header_key());
+                    is_new_key
+                })
+                .map(InterestingFields::to_json_member),
+        );
+        members.extend(self.report.to_json_members());
+
+        format!("{{{}}}", members.join(","))
+    }
+
+    /// Describe the summary as a row of the report-only stats table, with a "-" for any fields that weren't found.
+    /// Temperatures that are the defaults for the filament type are marked with a "*". The features column lists what
+    /// the print uses beyond a plain single color print, separated by "+".
+    pub fn to_report_row(&self) -> String {
+        let mut time: String = "-".to_string();
+        let mut filament_mm: String = "-".to_string();
+        let mut filament_m: String = "-".to_string();
+        let mut bed: String = "-".to_string();
+        let mut hotend: String = "-".to_string();
+        let mut has_thumbnail: bool = false;
+        let temperature = |celsius: u64, default: bool| -> String {
+            format!("{celsius}{}", if default { "*" } else { "" })
+        };
+
+        self.fields.iter().for_each(|field| match field {
+            InterestingFields::Time(seconds) => time = format_duration(*seconds),
+            InterestingFields::FilamentUsed(length_umx10) => {
+                filament_mm = format!("{:.2}", (*length_umx10 as f64) / 100.0);
+                filament_m = format!("{:.5}", (*length_umx10 as f64) / 100000.0);
+            }
+            InterestingFields::BedTemperature(celsius, default) => {
+                bed = temperature(*celsius, *default)
+            }
+            InterestingFields::HotendTemperature(celsius, default) => {
+                hotend = temperature(*celsius, *default)
+            }
+            InterestingFields::Thumbnail(..) => has_thumbnail = true,
+            InterestingFields::FilamentWeight(..)
+            | InterestingFields::Flavour(_)
+            | InterestingFields::PostProcessor(_)
+            | InterestingFields::LayerCount(_)
+            | InterestingFields::Bound(..)
+            | InterestingFields::Custom { .. } => {}
+        });
+
+        let features: Vec<&str> = [
+            (!self.report.color_changes.is_empty(), "color_changes"),
+            (self.report.total_toolchanges > 0, "toolchanges"),
+            (has_thumbnail, "thumbnail"),
+            (self.report.objects.is_some(), "objects_info"),
+        ]
+        .into_iter()
+        .filter_map(|(used, feature)| used.then_some(feature))
+        .collect();
+        let features: String = if features.is_empty() {
+            "-".to_string()
+        } else {
+            features.join("+")
+        };
+
+        format_report_row([
+            &time,
+            &filament_mm,
+            &filament_m,
+            &self.report.layer_count.to_string(),
+            &bed,
+            &hotend,
+            &self.report.filament_per_color_mm.len().to_string(),
+            &self.report.total_toolchanges.to_string(),
+            &features,
+            &self.path,
+        ])
+    }
+
+    /// Describe the summary as a row of the CSV summary, leaving any fields that weren't found empty
+    pub fn to_csv_row(&self) -> String {
+        let mut time_seconds: String = String::new();
+        let mut filament_m: String = String::new();
+        let mut flavour: String = String::new();
+
+        self.fields.iter().for_each(|field| match field {
+            InterestingFields::Time(seconds) => time_seconds = seconds.to_string(),
+            InterestingFields::FilamentUsed(length_umx10) => {
+                filament_m = format!("{:.5}", (*length_umx10 as f64) / 100000.0)
+            }
+            InterestingFields::Flavour(name) => flavour = to_csv_field(name),
+            InterestingFields::FilamentWeight(..)
+            | InterestingFields::PostProcessor(_)
+            | InterestingFields::LayerCount(_)
+            | InterestingFields::Bound(..)
+            | InterestingFields::BedTemperature(..)
+            | InterestingFields::HotendTemperature(..)
+            | InterestingFields::Thumbnail(..)
+            | InterestingFields::Custom { .. } => {}
+        });
+
+        format!(
+            "{},{},{},{},{},{}",
+            to_csv_field(&self.path),
+            time_seconds,
+            filament_m,
+            self.report.layer_count,
+            flavour,
+            self.report.total_toolchanges
+        )
+    }
+}
+
+/// Quote a CSV field if it contains anything that would otherwise break the row apart
+pub fn to_csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Write the CSV summary, one row per file, to the given path
+pub fn write_csv_summary(csv_path: &str, summaries: &[FileSummary]) -> std::io::Result<()> {
+    let mut csv_writer: BufWriter<File> = BufWriter::new(File::create(csv_path)?);
+
+    writeln!(csv_writer, "{CSV_HEADER}")?;
+    for summary in summaries {
+        writeln!(csv_writer, "{}", summary.to_csv_row())?;
+    }
+
+    csv_writer.flush()
+}
+
+/// Quote and escape a string so that it can be embedded in JSON output
+pub fn to_json_string(value: &str) -> String {
+    let mut json_string: String = String::with_capacity(value.len() + 2);
+    json_string.push('"');
+    for character in value.chars() {
+        match character {
+            '"' => json_string.push_str("\\\""),
+            '\\' => json_string.push_str("\\\\"),
+            '\n' => json_string.push_str("\\n"),
+            '\r' => json_string.push_str("\\r"),
+            '\t' => json_string.push_str("\\t"),
+            control if control.is_control() => {
+                json_string.push_str(&format!("\\u{:04x}", control as u32))
+            }
+            _ => json_string.push(character),
+        }
+    }
+    json_string.push('"');
+    json_string
+}
+
+/// A parsed JSON value, for reading the JSON that Prusaslicer embeds in some attributes
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsonValue {
+    /// `null`
+    Null,
+    /// `true` or `false`
+    Bool(bool),
+    /// Any number, integer or not
+    Number(f64),
+    /// A string, with its escapes resolved
+    String(String),
+    /// An array of values
+    Array(Vec<JsonValue>),
+    /// An object's members, in the order they were written
+    Object(Vec<(String, JsonValue)>),
+}
+
+impl JsonValue {
+    /// Parse a whole JSON document, returning `None` if it is malformed
+    pub fn parse(json: &str) -> Option<Self> {
+        let mut characters: Peekable<Chars> = json.chars().peekable();
+        let value: JsonValue = Self::parse_value(&mut characters)?;
+        Self::skip_whitespace(&mut characters);
+
+        characters.next().is_none().then_some(value)
+    }
+
+    /// Look up a member of an object by its key
+    pub fn get(&self, key: &str) -> Option<&JsonValue> {
+        match self {
+            JsonValue::Object(members) => members
+                .iter()
+                .find(|(member_key, _)| member_key == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    /// The value as a string, if it is one
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            JsonValue::String(string) => Some(string),
+            _ => None,
+        }
+    }
+
+    /// The value as a number, if it is one
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            JsonValue::Number(number) => Some(*number),
+            _ => None,
+        }
+    }
+
+    /// The value as an array, if it is one
+    pub fn as_array(&self) -> Option<&Vec<JsonValue>> {
+        match self {
+            JsonValue::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    fn skip_whitespace(characters: &mut Peekable<Chars>) {
+        while characters
+            .next_if(|character| character.is_whitespace())
+            .is_some()
+        {}
+    }
+
+    fn parse_value(characters: &mut Peekable<Chars>) -> Option<Self> {
+        Self::skip_whitespace(characters);
+        match characters.peek()? {
+            'n' => Self::parse_keyword(characters, "null", JsonValue::Null),
+            't' => Self::parse_keyword(characters, "true", JsonValue::Bool(true)),
+            'f' => Self::parse_keyword(characters, "false", JsonValue::Bool(false)),
+            '"' => Self::parse_string(characters).map(JsonValue::String),
+            '[' => {
+                characters.next();
+                let mut values: Vec<JsonValue> = Vec::new();
+                Self::parse_sequence(characters, ']', |characters| {
+                    values.push(Self::parse_value(characters)?);
+                    Some(())
+                })?;
+                Some(JsonValue::Array(values))
+            }
+            '{' => {
+                characters.next();
+                let mut members: Vec<(String, JsonValue)> = Vec::new();
+                Self::parse_sequence(characters, '}', |characters| {
+                    Self::skip_whitespace(characters);
+                    let key: String = Self::parse_string(characters)?;
+                    Self::skip_whitespace(characters);
+                    characters.next_if_eq(&':')?;
+                    members.push((key, Self::parse_value(characters)?));
+                    Some(())
+                })?;
+                Some(JsonValue::Object(members))
+            }
+            _ => {
+                let mut number: String = String::new();
+                while let Some(character) = characters.next_if(|character| {
+                    matches!(character, '0'..='9' | '-' | '+' | '.' | 'e' | 'E')
+                }) {
+                    number.push(character);
+                }
+                number.parse().ok().map(JsonValue::Number)
+            }
+        }
+    }
+
+    /// Parse the comma separated elements of an array or object, after its opening bracket, up to and including the
+    /// closing bracket
+    fn parse_sequence(
+        characters: &mut Peekable<Chars>,
+        closing_bracket: char,
+        mut parse_element: impl FnMut(&mut Peekable<Chars>) -> Option<()>,
+    ) -> Option<()> {
+        Self::skip_whitespace(characters);
+        if characters.next_if_eq(&closing_bracket).is_some() {
+            return Some(());
+        }
+
+        loop {
+            parse_element(characters)?;
+            Self::skip_whitespace(characters);
+            match characters.next()? {
+                ',' => continue,
+                character if character == closing_bracket => return Some(()),
+                _ => return None,
+            }
+        }
+    }
+
+    fn parse_keyword(
+        characters: &mut Peekable<Chars>,
+        keyword: &str,
+        value: JsonValue,
+    ) -> Option<Self> {
+        keyword
+            .chars()
+            .all(|expected| characters.next() == Some(expected))
+            .then_some(value)
+    }
+
+    fn parse_string(characters: &mut Peekable<Chars>) -> Option<String> {
+        characters.next_if_eq(&'"')?;
+
+        let mut string: String = String::new();
+        loop {
+            match characters.next()? {
+                '"' => return Some(string),
+                '\\' => match characters.next()? {
+                    'n' => string.push('\n'),
+                    'r' => string.push('\r'),
+                    't' => string.push('\t'),
+                    'b' => string.push('\u{8}'),
+                    'f' => string.push('\u{c}'),
+                    'u' => {
+                        let code: String = characters.by_ref().take(4).collect();
+                        let code: u32 = u32::from_str_radix(&code, 16).ok()?;
+                        // Surrogate pairs aren't worth combining for object names, so they're replaced
+                        string.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+                    }
+                    escaped => string.push(escaped),
+                },
+                character => string.push(character),
+            }
+        }
+    }
+}
+
+/// Given a line, attempt to parse the value into an integer number of seconds
+pub fn extract_time_data_as_seconds(attribute: &str) -> Result<u64, ParsingError> {
+    // After splitting on the equals sign, skipping the left hand side and trimming the resulting string
+    // we should just have "XXh YYm ZZs", or "Xd XXh YYm ZZs" for long prints. Prusaslicer sometimes follows it with a
+    // note in parentheses, e.g. "2h 3m (silent 2h 30m)", which is dropped.
+    let value = if let Some(string_value) = attribute.split('=').nth(1) {
+        string_value.split('(').next().unwrap_or_default().trim()
+    } else {
+        return Err(ParsingError::MissingValue(attribute.to_string()));
+    };
+
+    // Empty tokens come from doubled spaces, and don't contribute anything
+    value
+        .split(' ')
+        .filter(|token| !token.is_empty())
+        .map(|token| {
+            let (amount, unit_seconds) = if let Some(days) = token.strip_suffix('d') {
+                (days, 24 * 60 * 60)
+            } else if let Some(hours) = token.strip_suffix('h') {
+                (hours, 60 * 60)
+            } else if let Some(minutes) = token.strip_suffix('m') {
+                (minutes, 60)
+            } else if let Some(seconds) = token.strip_suffix('s') {
+                (seconds, 1)
+            } else {
+                return Err(ParsingError::StringParsingError(
+                    "duration",
+                    token.to_string(),
+                ));
+            };
+
+            // A day count large enough to overflow is as unparseable as one that isn't a number at all
+            amount
+                .parse::<u64>()
+                .ok()
+                .and_then(|amount| amount.checked_mul(unit_seconds))
+                .ok_or_else(|| ParsingError::StringParsingError("u64", token.to_string()))
+        })
+        .try_fold(0u64, |total, seconds| {
+            total
+                .checked_add(seconds?)
+                .ok_or_else(|| ParsingError::StringParsingError("u64", value.to_string()))
+        })
+}
+
+/// Given a line, attempt to extract how many 10s of micrometers of filament are predicted to be used. Multi-material
+/// prints list one length per extruder, e.g. "123.45, 678.90, 0.00", which are summed into a single total.
+pub fn extract_filament_used_as_um_x10(attribute: &str) -> Result<u64, ParsingError> {
+    // After splitting on the equals sign, skipping the left hand side and trimming the resulting string
+    // we should just have "XXXX.YY", our length in millimeters, or a comma separated list of them.
+    let value = if let Some(string_value) = attribute.split('=').nth(1) {
+        string_value.trim()
+    } else {
+        return Err(ParsingError::MissingValue(attribute.to_string()));
+    };
+
+    if is_thousands_separated(value) {
+        return parse_length_mm_as_um_x10(&value.replace(',', ""));
+    }
+
+    // Lengths that each fit but overflow once added up are as unparseable as a single length that doesn't fit
+    value
+        .split(',')
+        .map(|length| parse_length_mm_as_um_x10(length.trim()))
+        .try_fold(0u64, |total, length_umx10| {
+            total
+                .checked_add(length_umx10?)
+                .ok_or_else(|| ParsingError::StringParsingError("u64", value.to_string()))
+        })
+}
+
+/// Check whether the commas in a value group the digits of a single number into thousands, e.g. "1,234.56", rather than
+/// separating a list of values, e.g. "12.3,45.6". Every group after the first must be exactly three digits, with the
+/// decimal part only after the last group, and nothing else around the commas.
+pub fn is_thousands_separated(value: &str) -> bool {
+    let integer_part: &str = value
+        .split_once('.')
+        .map_or(value, |(integer_part, _)| integer_part);
+    let mut groups = integer_part.split(',');
+    let first_group: &str = groups.next().unwrap_or_default();
+    let is_digits = |group: &str| group.bytes().all(|byte| byte.is_ascii_digit());
+
+    integer_part.contains(',')
+        && (1..=3).contains(&first_group.len())
+        && is_digits(first_group)
+        && groups.all(|group| group.len() == 3 && is_digits(group))
+}
+
+/// Parse a single length in millimeters, e.g. "1234.56", into 10s of micrometers
+pub fn parse_length_mm_as_um_x10(value: &str) -> Result<u64, ParsingError> {
+    // More than one decimal place means the value is malformed, and concatenating the parts would silently give a
+    // nonsense length
+    if value.matches('.').count() > 1 {
+        return Err(ParsingError::StringParsingError("u64", value.to_string()));
+    }
+
+    // Split on the decimal place and pad the fractional part out to hundredths, so that "1200" and "1200.5" scale the
+    // same as "1200.00", then collect back into a string, which we should be able to parse into an integer value. Any
+    // finer precision than hundredths can't be represented, so is rejected rather than silently dropped.
+    let (integer_part, fractional_part) = value.split_once('.').unwrap_or((value, ""));
+    if fractional_part.len() > 2 {
+        return Err(ParsingError::StringParsingError("u64", value.to_string()));
+    }
+    let integer_value_str: String = format!("{integer_part}{fractional_part:0<2}");
+
+    if let Ok(parsed_integer) = integer_value_str.parse() {
+        Ok(parsed_integer)
+    } else {
+        Err(ParsingError::StringParsingError("u64", value.to_string()))
+    }
+}
+
+/// Check whether the attribute is the legacy filament used attribute, rather than the modern one that shares its prefix
+pub fn is_legacy_filament_used(attribute: &str) -> bool {
+    attribute
+        .split('=')
+        .next()
+        .is_some_and(|key| key.trim().eq_ignore_ascii_case(PRUSA_LEGACY_FILAMENT_USED))
+}
+
+/// Given a legacy filament used line, e.g. "filament used = 1234.5mm (3.0cm3)", attempt to extract how many 10s of
+/// micrometers of filament are predicted to be used.
+pub fn extract_legacy_filament_used_as_um_x10(attribute: &str) -> Result<u64, ParsingError> {
+    // The volume follows the length, so only the first word after the equals sign is the length
+    let value: &str = match attribute
+        .split('=')
+        .nth(1)
+        .and_then(|value| value.split_whitespace().next())
+    {
+        Some(value) => value.trim_end_matches("mm"),
+        None => return Err(ParsingError::MissingValue(attribute.to_string())),
+    };
+
+    match value.parse::<f64>() {
+        Ok(length_mm) if length_mm.is_finite() && length_mm >= 0.0 => {
+            Ok((length_mm * 100.0).round() as u64)
+        }
+        _ => Err(ParsingError::StringParsingError("f64", value.to_string())),
+    }
+}
+
+/// Read a single line, keeping at most `max_line_length` bytes of it plus its newline, if it has one. Anything past the
+/// limit is consumed from the reader without being stored. Any bytes that aren't valid UTF-8, e.g. a Latin-1 object name
+/// in a comment, are replaced with U+FFFD rather than losing the rest of the file. Returns `None` at the end of the input,
+/// otherwise the line and whether it was truncated.
+pub fn read_bounded_line(
+    reader: &mut impl BufRead,
+    max_line_length: usize,
+) -> std::io::Result<Option<(String, bool)>> {
+    let mut line: Vec<u8> = Vec::new();
+    let mut truncated: bool = false;
+    let mut read_anything: bool = false;
+    let mut terminated: bool = false;
+
+    loop {
+        let available: &[u8] = reader.fill_buf()?;
+        if available.is_empty() {
+            break;
+        }
+        read_anything = true;
+
+        let newline_position: Option<usize> = available.iter().position(|byte| *byte == b'\n');
+        let line_part: &[u8] = &available[..newline_position.unwrap_or(available.len())];
+
+        let space_left: usize = max_line_length.saturating_sub(line.len());
+        if line_part.len() > space_left {
+            truncated = true;
+        }
+        line.extend_from_slice(&line_part[..line_part.len().min(space_left)]);
+
+        // Also consume the newline itself, if we found one
+        let consumed: usize = newline_position.map_or(available.len(), |position| position + 1);
+        reader.consume(consumed);
+
+        if newline_position.is_some() {
+            terminated = true;
+            break;
+        }
+    }
+
+    if !read_anything {
+        return Ok(None);
+    }
+
+    let mut line: String = match String::from_utf8(line) {
+        Ok(line) => line,
+        // Truncating may have split a multi-byte character, so only drop the partial character at the very end
+        Err(utf8_error) if truncated && utf8_error.utf8_error().error_len().is_none() => {
+            let valid_length: usize = utf8_error.utf8_error().valid_up_to();
+            let mut bytes: Vec<u8> = utf8_error.into_bytes();
+            bytes.truncate(valid_length);
+            String::from_utf8(bytes).expect("Bytes were validated up to this length")
+        }
+        Err(utf8_error) => String::from_utf8_lossy(utf8_error.as_bytes()).into_owned(),
+    };
+    if terminated {
+        line.push('\n');
+    }
+
+    Ok(Some((line, truncated)))
+}
+
+/// Read every line of the file, without their line endings. A line longer than the configured maximum fails the whole
+/// file, unless `options.lenient` is given, in which case it is truncated. A UTF-8 byte order mark at the start of the
+/// file, as some Windows editors add, is dropped so that it doesn't become part of the first line. It isn't written back
+/// out. Also returns how the lines were laid out in the file.
+pub fn read_lines(
+    mut reader: impl BufRead,
+    options: &Options,
+    log: &mut FileLog,
+) -> Result<(Vec<String>, TextLayout), ParsingError> {
+    // Fail on the first read error, rather than converting only part of the file. It is unlikely to go away by retrying.
+    let mut lines: Vec<String> = Vec::new();
+    let mut crlf_lines: usize = 0;
+    let mut trailing_newline: bool = false;
+    while let Some((mut line, truncated)) = read_bounded_line(&mut reader, options.max_line_length)
+        .map_err(|file_reading_error| ParsingError::Io(format!("{file_reading_error}")))?
+    {
+        if lines.is_empty() && line.starts_with(UTF8_BOM) {
+            line.remove(0);
+        }
+        trailing_newline = line.ends_with('\n');
+        if trailing_newline {
+            line.pop();
+        }
+        if line.ends_with('\r') {
+            line.pop();
+            crlf_lines += 1;
+        }
+        if truncated && !options.lenient {
+            return Err(ParsingError::LineTooLong {
+                line: lines.len() + 1,
+                max_line_length: options.max_line_length,
+            });
+        }
+        if truncated {
+            log.diagnostics.push(Diagnostic::LineTruncated {
+                line: lines.len() + 1,
+                max_line_length: options.max_line_length,
+            });
+        }
+        lines.push(line);
+    }
+
+    let line_ending: LineEnding = if crlf_lines * 2 > lines.len() {
+        LineEnding::CrLf
+    } else {
+        LineEnding::Lf
+    };
+    Ok((
+        lines,
+        TextLayout {
+            line_ending,
+            trailing_newline,
+        },
+    ))
+}
+
+/// Check whether the file has the given extension, ignoring case, or starts with the given magic bytes. The bytes are
+/// only peeked at, so are still there to be read afterwards.
+pub fn has_extension_or_magic(
+    file_path: &Path,
+    reader: &mut impl BufRead,
+    extension: &str,
+    magic: &[u8],
+) -> std::io::Result<bool> {
+    let has_extension: bool = file_path
+        .extension()
+        .is_some_and(|file_extension| file_extension.eq_ignore_ascii_case(extension));
+
+    Ok(has_extension || reader.fill_buf()?.starts_with(magic))
+}
+
+/// Check whether the file is Prusaslicer's binary gcode. Binary gcode isn't text, so reading it line by line would
+/// mangle it.
+pub fn is_binary_gcode(file_path: &Path, reader: &mut impl BufRead) -> std::io::Result<bool> {
+    has_extension_or_magic(file_path, reader, BGCODE_EXTENSION, BGCODE_MAGIC)
+}
+
+/// Check whether the file is gzip compressed
+pub fn is_gzip(file_path: &Path, reader: &mut impl BufRead) -> std::io::Result<bool> {
+    has_extension_or_magic(file_path, reader, GZIP_EXTENSION, GZIP_MAGIC)
+}
+
+/// Check whether the file is zstd compressed
+pub fn is_zstd(file_path: &Path, reader: &mut impl BufRead) -> std::io::Result<bool> {
+    has_extension_or_magic(file_path, reader, ZSTD_EXTENSION, ZSTD_MAGIC)
+}
+
+/// How a gcode file is compressed. Compressed files are decompressed to be converted, and the converted file is
+/// compressed the same way, provided the feature for the compression is enabled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// gzip, e.g. "print.gcode.gz"
+    Gzip,
+    /// zstd, e.g. "print.gcode.zst"
+    Zstd,
+}
+
+impl Compression {
+    /// Work out how the file is compressed, if at all, by its extension or the magic bytes at the start of it
+    pub fn detect(file_path: &Path, reader: &mut impl BufRead) -> std::io::Result<Option<Self>> {
+        if is_gzip(file_path, reader)? {
+            return Ok(Some(Compression::Gzip));
+        }
+        Ok(is_zstd(file_path, reader)?.then_some(Compression::Zstd))
+    }
+
+    /// The Cargo feature needed to read and write files compressed this way
+    pub fn feature(self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        }
+    }
+}
+
+impl std::fmt::Display for Compression {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Compression::Gzip => write!(formatter, "gzip"),
+            Compression::Zstd => write!(formatter, "zstd"),
+        }
+    }
+}
+
+/// Check whether the file contains any gcode instructions, rather than just comments and blank lines.
+pub fn has_gcode_body(lines: &[LineKind]) -> bool {
+    lines
+        .iter()
+        .any(|line| matches!(line, LineKind::Instruction(_)))
+}
+
+/// Sanity check the nozzle and filament diameters in the config, returning a warning if they look misconfigured, e.g.
+/// swapped. This is only advisory, the file is still processed. Only the first extruder's values are checked.
+pub fn diameter_warning(lines: &[LineKind]) -> Option<Diagnostic> {
+    let first_value = |attribute: &str| -> Option<f64> {
+        attribute
+            .split('=')
+            .nth(1)?
+            .split(',')
+            .next()?
+            .trim()
+            .parse()
+            .ok()
+    };
+
+    let mut nozzle_diameter: Option<f64> = None;
+    let mut filament_diameter: Option<f64> = None;
+    lines.iter().for_each(|line| {
+        if let LineKind::Metadata(attribute) = line {
+            if has_attribute_key(attribute, PRUSA_NOZZLE_DIAMETER) {
+                nozzle_diameter = first_value(attribute);
+            } else if has_attribute_key(attribute, PRUSA_FILAMENT_DIAMETER) {
+                filament_diameter = first_value(attribute);
+            }
+        }
+    });
+
+    match (nozzle_diameter?, filament_diameter) {
+        (nozzle_diameter, Some(filament_diameter)) if nozzle_diameter >= filament_diameter => {
+            Some(Diagnostic::SwappedDiameters {
+                nozzle_diameter,
+                filament_diameter,
+            })
+        }
+        (nozzle_diameter, _) if nozzle_diameter < MIN_PLAUSIBLE_NOZZLE_DIAMETER => {
+            Some(Diagnostic::ImplausibleNozzleDiameter(nozzle_diameter))
+        }
+        _ => None,
+    }
+}
+
+/// Count the layers in the print from Prusaslicer's layer change comments, one of which starts every layer including the
+/// first. The ";Z:" comment that follows each one isn't counted again.
+pub fn count_layers(lines: &[LineKind]) -> u64 {
+    lines
+        .iter()
+        .filter(|line| matches!(line, LineKind::Comment(comment) if comment.trim() == PRUSA_LAYER_CHANGE))
+        .count() as u64
+}
+
+/// Total the filament extruded by every move in the file, in millimeters, following the switches between relative (M83)
+/// and absolute (M82) extrusion. Retractions are subtracted again, so only what's actually pushed out is counted.
+pub fn total_extruded_mm(lines: &[LineKind]) -> f64 {
+    let mut extrusion: ExtrusionTracker = ExtrusionTracker::default();
+    lines
+        .iter()
+        .map(|line| match line {
+            LineKind::Instruction(instruction) => extrusion.extruded_by(instruction),
+            _ => 0.0,
+        })
+        .sum()
+}
+
+/// Cross check Prusaslicer's filament estimate against what the moves in the file extrude, returning a warning if they
+/// differ by more than [`FILAMENT_MISMATCH_TOLERANCE`]. Files that don't extrude anything aren't checked.
+pub fn filament_mismatch_warning(
+    lines: &[LineKind],
+    interesting_fields: &[InterestingFields],
+) -> Option<Diagnostic> {
+    let estimated_mm: f64 = interesting_fields.iter().find_map(|field| match field {
+        InterestingFields::FilamentUsed(length_umx10) => Some((*length_umx10 as f64) / 100.0),
+        _ => None,
+    })?;
+    let extruded_mm: f64 = total_extruded_mm(lines);
+
+    (extruded_mm > 0.0
+        && (extruded_mm - estimated_mm).abs() > estimated_mm * FILAMENT_MISMATCH_TOLERANCE)
+        .then_some(Diagnostic::FilamentMismatch {
+            estimated_mm,
+            extruded_mm,
+        })
+}
+
+/// Find the version of Prusaslicer that generated the file, e.g. "2.6.0+linux-x64-GTK3", if it recorded one
+pub fn prusaslicer_version<'a>(lines: &[LineKind<'a>]) -> Option<&'a str> {
+    lines.iter().find_map(|line| match line {
+        LineKind::Comment(comment) => comment
+            .strip_prefix(PRUSA_GENERATED_BY)?
+            .split_whitespace()
+            .next(),
+        _ => None,
+    })
+}
+
+/// Check whether a Prusaslicer version is newer than [`LATEST_TESTED_PRUSASLICER_VERSION`]. Versions that can't be
+/// parsed are assumed to be fine, as there is nothing useful to warn about.
+pub fn is_untested_prusaslicer_version(version: &str) -> bool {
+    let mut parts = version
+        .split(|character: char| !character.is_ascii_digit())
+        .map(|part| part.parse::<u64>().ok());
+
+    match (parts.next().flatten(), parts.next().flatten()) {
+        (Some(major), Some(minor)) => (major, minor) > LATEST_TESTED_PRUSASLICER_VERSION,
+        _ => false,
+    }
+}
+
+/// Translate a Prusaslicer gcode flavour into the flavour name the Ankermake M5 expects, if it has one.
+pub fn translate_gcode_flavor(prusa_flavor: &str) -> Option<&'static str> {
+    match prusa_flavor.trim() {
+        "marlin" | "marlin2" => Some("Marlin"),
+        _ => None,
+    }
+}
+
+/// Where Prusaslicer's config block sits in the file, by line index
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConfigBlock {
+    /// Index of the "; prusaslicer_config = begin" line
+    pub begin: usize,
+    /// Index of the "; prusaslicer_config = end" line, if the file wasn't cut short before it
+    pub end: Option<usize>,
+}
+
+impl ConfigBlock {
+    /// Find the config block's markers among the lines of the file, if it has a config block
+    pub fn find(lines: &[LineKind]) -> Option<Self> {
+        let is_marker = |line: &LineKind, marker: &str| matches!(line, LineKind::Metadata(attribute) if has_attribute_key(attribute, marker));
+
+        let begin: usize = lines
+            .iter()
+            .position(|line| is_marker(line, PRUSA_CONFIG_BEGIN))?;
+        let end: Option<usize> = lines[begin..]
+            .iter()
+            .position(|line| is_marker(line, PRUSA_CONFIG_END))
+            .map(|end| begin + end);
+
+        Some(ConfigBlock { begin, end })
+    }
+
+    /// The lines making up the block, from its begin marker up to and including its end marker. If the end marker is
+    /// missing, the block runs to the end of the file.
+    pub fn range(&self, line_count: usize) -> Range<usize> {
+        self.begin..self.end.map_or(line_count, |end| end + 1)
+    }
+
+    /// Whether the config block is complete
+    pub fn is_closed(&self) -> bool {
+        self.end.is_some()
+    }
+}
+
+/// Heuristically check whether the file was cut short, e.g. by a failed SD card transfer. Prusaslicer writes its
+/// estimates and config block at the very end of the file, so a truncated file is missing both estimates or is missing
+/// the end of the config block.
+pub fn looks_truncated(lines: &[LineKind]) -> bool {
+    let has_estimate: bool = lines.iter().any(|line| match line {
+        LineKind::Metadata(attribute) => {
+            has_attribute_key(attribute, PRUSA_ESTIMATED_PRINTING_TIME)
+                || has_attribute_key(attribute, PRUSA_FILAMENT_USED_MM)
+                || is_legacy_filament_used(attribute)
+        }
+        _ => false,
+    });
+
+    !has_estimate || ConfigBlock::find(lines).is_some_and(|config_block| !config_block.is_closed())
+}
+
+/// Pull out the attributes that we're interested in, in the order that they should appear in the header. That order is
+/// fixed so that converted files can be diffed: the flavour, the post-processor version, the time, the filament used, its
+/// weight, the bed and hotend temperatures, the layer count, the bounds from MINX to MAXZ and then the large and small
+/// thumbnails.
+/// The built-in fields come first, followed by anything the extractors in `options` find.
+///
+/// Prusaslicer estimates the time for both normal and silent mode, only the normal mode estimate is used, or the first
+/// estimate if there is none for normal mode. Estimates that can't be parsed are left out, and returned alongside the
+/// fields that could be, with their line numbers counting from 1. With `options.fast` the bounds and thumbnails are left
+/// out, as finding them means following every move and decoding every thumbnail.
+pub fn extract_interesting_fields(
+    lines: &[LineKind],
+    options: &Options,
+) -> (Vec<InterestingFields>, Vec<(usize, ParsingError)>) {
+    let mut interesting_fields: Vec<InterestingFields> = vec![
+        InterestingFields::Flavour(ANKERMAKE_DEFAULT_FLAVOUR.into()),
+        InterestingFields::PostProcessor(version()),
+    ];
+    let mut printing_time: Option<u64> = None;
+    let mut has_normal_mode_time: bool = false;
+    let mut filament_used: Option<u64> = None;
+    let mut has_filament_used: bool = false;
+    let mut legacy_filament_used: Option<u64> = None;
+    let mut max_layer_z: Option<f64> = None;
+    let mut bed_temperature: Option<u64> = None;
+    let mut hotend_temperature: Option<u64> = None;
+    let mut filament_type: Option<&str> = None;
+    let mut filament_weight: Option<u64> = None;
+    let mut filament_diameter: Option<f64> = None;
+    let mut filament_density: Option<f64> = None;
+    let mut parsing_errors: Vec<(usize, ParsingError)> = Vec::new();
+
+    for (index, line) in lines.iter().enumerate() {
+        if let LineKind::Metadata(attribute) = line {
+            if has_attribute_key(attribute, PRUSA_GCODE_FLAVOR) {
+                // The flavour is always first in the header, replace the default if we know the translation
+                if let Some(flavour) = attribute.split('=').nth(1).and_then(translate_gcode_flavor)
+                {
+                    interesting_fields[0] = InterestingFields::Flavour(flavour.into());
+                }
+            } else if has_attribute_key(attribute, PRUSA_ESTIMATED_PRINTING_TIME) {
+                let is_normal_mode: bool = attribute
+                    .split('=')
+                    .next()
+                    .is_some_and(|key| key.to_ascii_lowercase().contains(PRUSA_NORMAL_MODE));
+                match extract_time_data_as_seconds(attribute) {
+                    Ok(seconds) if is_normal_mode && !has_normal_mode_time => {
+                        printing_time = Some(seconds);
+                        has_normal_mode_time = true;
+                    }
+                    Ok(seconds) => {
+                        printing_time = printing_time.or(Some(seconds));
+                    }
+                    Err(parsing_error) => parsing_errors.push((index + 1, parsing_error)),
+                }
+            } else if has_attribute_key(attribute, PRUSA_FILAMENT_USED_MM) {
+                has_filament_used = true;
+                match extract_filament_used_as_um_x10(attribute) {
+                    Ok(length_umx10) => filament_used = filament_used.or(Some(length_umx10)),
+                    Err(parsing_error) => parsing_errors.push((index + 1, parsing_error)),
+                }
+            } else if has_attribute_key(attribute, PRUSA_FILAMENT_USED_G) {
+                // Grams are written in the same fixed point form as millimeters, so parse to hundredths the same way
+                filament_weight = extract_filament_used_as_um_x10(attribute).ok();
+            } else if is_legacy_filament_used(attribute) {
+                legacy_filament_used = extract_legacy_filament_used_as_um_x10(attribute).ok();
+            } else if has_attribute_key(attribute, PRUSA_MAX_LAYER_Z) {
+                max_layer_z = parse_attribute_value(attribute);
+            } else if has_attribute_key(attribute, PRUSA_BED_TEMPERATURE) {
+                bed_temperature = first_extruder_temperature(attribute);
+            } else if has_attribute_key(attribute, PRUSA_TEMPERATURE) {
+                hotend_temperature = first_extruder_temperature(attribute);
+            } else if has_attribute_key(attribute, PRUSA_FILAMENT_DIAMETER) {
+                filament_diameter = first_extruder_value(attribute);
+            } else if has_attribute_key(attribute, PRUSA_FILAMENT_DENSITY) {
+                filament_density = first_extruder_value(attribute);
+            } else if has_attribute_key(attribute, PRUSA_FILAMENT_TYPE) {
+                // Prusaslicer separates the values of text attributes with semicolons
+                filament_type = attribute
+                    .split('=')
+                    .nth(1)
+                    .and_then(|value| value.split(';').next())
+                    .map(str::trim);
+            }
+        }
+    }
+
+    interesting_fields.extend(printing_time.map(InterestingFields::Time));
+    // Older files only have the legacy attribute, which is ignored if the modern one was found
+    let filament_used: Option<u64> =
+        filament_used.or(legacy_filament_used.filter(|_| !has_filament_used));
+    interesting_fields.extend(filament_used.map(InterestingFields::FilamentUsed));
+
+    if options.filament_weight {
+        // Prusaslicer leaves the density as 0 when it isn't set, which is no better than not having it
+        let density: Option<f64> = filament_density
+            .filter(|density| *density > 0.0)
+            .or(filament_type.and_then(default_filament_density));
+        let estimated_weight: Option<u64> =
+            filament_used.zip(density).map(|(length_umx10, density)| {
+                estimate_filament_weight_as_cg(
+                    length_umx10,
+                    filament_diameter.unwrap_or(DEFAULT_FILAMENT_DIAMETER_MM),
+                    density,
+                )
+            });
+        interesting_fields.extend(
+            filament_weight
+                .map(|weight_cg| (weight_cg, false))
+                .or(estimated_weight.map(|weight_cg| (weight_cg, true)))
+                .map(|(weight_cg, estimated)| {
+                    InterestingFields::FilamentWeight(weight_cg, estimated)
+                }),
+        );
+    }
+
+    // Incomplete exports may only record the filament type, whose usual temperatures are better than none
+    let default_temperatures: Option<(u64, u64)> = filament_type.and_then(default_temperatures);
+    interesting_fields.extend(
+        bed_temperature
+            .map(|celsius| (celsius, false))
+            .or(default_temperatures.map(|(_, celsius)| (celsius, true)))
+            .map(|(celsius, default)| InterestingFields::BedTemperature(celsius, default)),
+    );
+    interesting_fields.extend(
+        hotend_temperature
+            .map(|celsius| (celsius, false))
+            .or(default_temperatures.map(|(celsius, _)| (celsius, true)))
+            .map(|(celsius, default)| InterestingFields::HotendTemperature(celsius, default)),
+    );
+
+    let layer_count: u64 = count_layers(lines);
+    if layer_count > 0 {
+        interesting_fields.push(InterestingFields::LayerCount(layer_count));
+    }
+
+    let bounding_box: Option<BoundingBox> = if options.fast {
+        None
+    } else {
+        BoundingBox::from_lines(lines)
+    };
+    if let Some(bounding_box) = bounding_box {
+        interesting_fields.extend(bounding_box.to_fields(max_layer_z));
+    }
+
+    // Prusaslicer can embed several sizes, the largest makes the best preview and the smallest the best icon. With only
+    // one thumbnail it's used for both.
+    let thumbnails: Vec<Thumbnail> = if options.fast {
+        Vec::new()
+    } else {
+        Thumbnail::from_lines(lines)
+    };
+    if let (Some(largest), Some(smallest)) = (
+        thumbnails.iter().max_by_key(|thumbnail| thumbnail.area()),
+        thumbnails.iter().min_by_key(|thumbnail| thumbnail.area()),
+    ) {
+        interesting_fields.push(InterestingFields::Thumbnail(
+            ANKERMAKE_LARGE_THUMBNAIL,
+            largest.clone(),
+        ));
+        interesting_fields.push(InterestingFields::Thumbnail(
+            ANKERMAKE_SMALL_THUMBNAIL,
+            smallest.clone(),
+        ));
+    }
+
+    options.extractors.iter().for_each(|extractor| {
+        interesting_fields.extend(lines.iter().filter_map(|line| extractor.try_extract(line)));
+    });
+
+    (interesting_fields, parsing_errors)
+}
+
+/// Parse the first extruder's value of a temperature config attribute, e.g. 215 from "temperature = 215,210". A value
+/// that isn't a whole number of degrees is left out of the header rather than failing the file, as it isn't an estimate.
+pub fn first_extruder_temperature(attribute: &str) -> Option<u64> {
+    first_extruder_value(attribute)
+}
+
+/// Parse the first extruder's value of a config attribute with one comma separated value per extruder, e.g. 1.75 from
+/// "filament_diameter = 1.75,2.85"
+pub fn first_extruder_value<T: FromStr>(attribute: &str) -> Option<T> {
+    attribute
+        .split('=')
+        .nth(1)?
+        .split(',')
+        .next()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// The usual density of a Prusaslicer filament type in grams per cubic centimeter, if it is one of
+/// [`DEFAULT_FILAMENT_DENSITIES`]
+pub fn default_filament_density(filament_type: &str) -> Option<f64> {
+    DEFAULT_FILAMENT_DENSITIES
+        .into_iter()
+        .find(|(known_type, _)| known_type.eq_ignore_ascii_case(filament_type))
+        .map(|(_, density)| density)
+}
+
+/// Estimate the weight of a length of filament, in hundredths of a gram, from its length in um x10, its diameter in
+/// millimeters and its density in grams per cubic centimeter
+pub fn estimate_filament_weight_as_cg(length_umx10: u64, diameter_mm: f64, density: f64) -> u64 {
+    let volume_mm3: f64 =
+        (length_umx10 as f64) / 100.0 * std::f64::consts::PI * (diameter_mm / 2.0).powi(2);
+    (volume_mm3 / 1000.0 * density * 100.0).round() as u64
+}
+
+/// The usual hotend and bed temperatures for a Prusaslicer filament type, in degrees Celsius, if it is one of
+/// [`DEFAULT_TEMPERATURES`]
+pub fn default_temperatures(filament_type: &str) -> Option<(u64, u64)> {
+    DEFAULT_TEMPERATURES
+        .into_iter()
+        .find(|(known_type, _, _)| known_type.eq_ignore_ascii_case(filament_type))
+        .map(|(_, hotend_celsius, bed_celsius)| (hotend_celsius, bed_celsius))
+}
+
+/// Extract the interesting fields, failing with every estimate that can't be parsed rather than writing a header that's
+/// missing them. With `options.lenient` those estimates are logged and skipped instead.
+pub fn extract_fields_or_fail(
+    lines: &[LineKind],
+    options: &Options,
+    log: &mut FileLog,
+) -> Result<Vec<InterestingFields>, ParsingError> {
+    let (interesting_fields, parsing_errors) = extract_interesting_fields(lines, options);
+    if options.lenient {
+        log.diagnostics.extend(
+            parsing_errors
+                .into_iter()
+                .map(|(line, error)| Diagnostic::SkippedLine { line, error }),
+        );
+    } else if !parsing_errors.is_empty() {
+        return Err(ParsingError::InvalidLines(parsing_errors));
+    }
+
+    Ok(interesting_fields)
+}
+
+/// Process the lines in the file, pulling out the attributes that we're interested in and reinserting them in the header for the
+/// file. Returns the new file contents that should be written to the disk.
+pub fn process_lines(
+    reader: impl BufRead,
+    options: &Options,
+    log: &mut FileLog,
+) -> Result<String, ParsingError> {
+    let (lines, layout) = read_lines(reader, options, log)?;
+    convert_lines(lines, layout, options, log)
+}
+
+/// Convert the gcode read from `reader` with the default options, writing the converted file to `writer`. Any warnings
+/// about the file are dropped, use [`process_reader`] to get hold of them.
+pub fn process<R: BufRead, W: Write>(reader: R, writer: W) -> Result<(), ParsingError> {
+    let options: Options = Options::default();
+    let mut log: FileLog = FileLog::default();
+    let (lines, layout) = read_lines(reader, &options, &mut log)?;
+    convert_to_writer(&lines, layout, &options, &mut log, writer)
+}
+
+/// The Ankermake header generated for a file
+#[derive(Debug, Default)]
+pub struct Header {
+    /// The header lines, in the order they're written
+    pub lines: Vec<String>,
+    /// The keys of the header fields that were generated, including any set on the command line
+    pub keys: Vec<&'static str>,
+}
+
+/// Generate the Ankermake header lines for the file from the fields already extracted from it, logging any problems with
+/// the file.
+pub fn generate_header(
+    line_kinds: &[LineKind],
+    interesting_fields: &[InterestingFields],
+    options: &Options,
+    log: &mut FileLog,
+) -> Header {
+    if !options.allow_empty_body && !has_gcode_body(line_kinds) {
+        log.diagnostics.push(Diagnostic::NoGcodeBody);
+    }
+    if looks_truncated(line_kinds) {
+        log.diagnostics.push(Diagnostic::LooksTruncated);
+    }
+    log.diagnostics.extend(diameter_warning(line_kinds));
+    if let Some(version) =
+        prusaslicer_version(line_kinds).filter(|version| is_untested_prusaslicer_version(version))
+    {
+        log.diagnostics
+            .push(Diagnostic::UntestedSlicerVersion(version.to_string()));
+    }
+
+    if !options.fast {
+        log.diagnostics
+            .extend(filament_mismatch_warning(line_kinds, interesting_fields));
+    }
+    if interesting_fields.iter().any(|field| {
+        matches!(
+            field,
+            InterestingFields::BedTemperature(_, true)
+                | InterestingFields::HotendTemperature(_, true)
+        )
+    }) {
+        log.diagnostics.push(Diagnostic::DefaultTemperatures);
+    }
+    let mut generated_keys: Vec<&'static str> = interesting_fields
+        .iter()
+        .map(InterestingFields::header_key)
+        .collect();
+
+    // Values set on the command line replace the generated line for their key, or are added after the generated lines
+    // if there isn't one
+    let mut header: Vec<String> = interesting_fields
+        .iter()
+        .flat_map(|val| {
+            match options
+                .header_overrides
+                .iter()
+                .find(|(key, _)| *key == val.header_key())
+            {
+                Some((key, value)) => vec![format!(";{key}:{value}")],
+                None if matches!(val, InterestingFields::FilamentWeight(_, true)) => vec![
+                    ANKERMAKE_ESTIMATED_WEIGHT_NOTE.to_string(),
+                    val.to_header_line(options),
+                ],
+                None => vec![val.to_header_line(options)],
+            }
+        })
+        .collect();
+    options.header_overrides.iter().for_each(|(key, value)| {
+        if !generated_keys.contains(key) {
+            header.push(format!(";{key}:{value}"));
+            generated_keys.push(key);
+        }
+    });
+
+    log.diagnostics.extend(
+        [ANKERMAKE_PRINTING_TIME, ANKERMAKE_FILAMENT_USED_M]
+            .into_iter()
+            .filter(|key| !generated_keys.contains(key))
+            .map(Diagnostic::MissingField),
+    );
+
+    if options.info_block {
+        header.insert(0, ANKERMAKE_INFO_BLOCK_BEGIN.to_string());
+        header.push(ANKERMAKE_INFO_BLOCK_END.to_string());
+    }
+
+    Header {
+        lines: header,
+        keys: generated_keys,
+    }
+}
+
+/// Convert lines that have already been read from the file, returning the new file contents.
+pub fn convert_lines(
+    lines: Vec<String>,
+    layout: TextLayout,
+    options: &Options,
+    log: &mut FileLog,
+) -> Result<String, ParsingError> {
+    let mut file_contents: Vec<u8> = Vec::new();
+    convert_to_writer(&lines, layout, options, log, &mut file_contents)?;
+    Ok(String::from_utf8(file_contents).expect("every line written was already a valid string"))
+}
+
+/// Classify the lines, extract their fields and generate the header from them, then write the converted file to
+/// `writer`
+fn convert_to_writer(
+    lines: &[String],
+    layout: TextLayout,
+    options: &Options,
+    log: &mut FileLog,
+    writer: impl Write,
+) -> Result<(), ParsingError> {
+    let line_kinds: Vec<LineKind> = classify_lines(lines);
+    let interesting_fields: Vec<InterestingFields> =
+        extract_fields_or_fail(&line_kinds, options, log)?;
+    let header: Header = generate_header(&line_kinds, &interesting_fields, options, log);
+    write_converted_lines(lines, &line_kinds, &header, layout, options, writer)
+        .map_err(|file_writing_error| ParsingError::Io(format!("{file_writing_error}")))
+}
+
+/// Write the converted file to `writer`, the header followed by the lines that have already been read from the file, a
+/// line at a time rather than building them up in memory first. Every line, including the header, uses the line ending
+/// of the original file, which also decides whether the new file ends with one.
+pub fn write_converted_lines(
+    lines: &[String],
+    line_kinds: &[LineKind],
+    header: &Header,
+    layout: TextLayout,
+    options: &Options,
+    writer: impl Write,
+) -> std::io::Result<()> {
+    // The header was generated from every line, including the config block, before anything is stripped
+    let stripped_config: Range<usize> = ConfigBlock::find(line_kinds)
+        .filter(|_| options.strip_prusa_config)
+        .map(|config_block| config_block.range(line_kinds.len()))
+        .unwrap_or_default();
+
+    let start_gcode: Vec<String> = if options.start_gcode.is_empty() {
+        Vec::new()
+    } else {
+        std::iter::once(START_GCODE_BEGIN.to_string())
+            .chain(options.start_gcode.iter().cloned())
+            .chain(std::iter::once(START_GCODE_END.to_string()))
+            .collect()
+    };
+
+    // Existing Ankermake annotations for the fields we've just generated would duplicate or contradict them, so drop
+    // those. Any we couldn't generate ourselves are kept as they are. Likewise start gcode from an earlier run is dropped
+    // in favour of the start gcode we've just inserted, and an earlier info block is dropped once it would be empty.
+    let emptied_info_block: Vec<usize> = emptied_info_block_delimiters(lines, &header.keys);
+    let mut in_start_gcode: bool = false;
+    let kept_lines = lines.iter().enumerate().filter(|&(index, line)| {
+        if stripped_config.contains(&index) || emptied_info_block.contains(&index) {
+            return false;
+        }
+
+        if !options.start_gcode.is_empty() {
+            if line == START_GCODE_BEGIN {
+                in_start_gcode = true;
+            }
+            if in_start_gcode {
+                in_start_gcode = line != START_GCODE_END;
+                return false;
+            }
+        }
+
+        if is_regenerated_line(line, &header.keys) {
+            return false;
+        }
+
+        let is_info_block_delimiter: bool =
+            line == ANKERMAKE_INFO_BLOCK_BEGIN || line == ANKERMAKE_INFO_BLOCK_END;
+        !(options.info_block && is_info_block_delimiter)
+    });
+    let kept_lines = kept_lines.map(|(_, line)| line);
+
+    let mut file_writer: BufWriter<_> = BufWriter::new(writer);
+    let write_lines = || -> std::io::Result<()> {
+        for (index, line) in header
+            .lines
+            .iter()
+            .chain(&start_gcode)
+            .chain(kept_lines)
+            .enumerate()
+        {
+            if index > 0 {
+                file_writer.write_all(layout.line_ending.as_str().as_bytes())?;
+            }
+            file_writer.write_all(line.as_bytes())?;
+        }
+        if layout.trailing_newline {
+            file_writer.write_all(layout.line_ending.as_str().as_bytes())?;
+        }
+        file_writer.flush()
+    };
+    write_lines()
+}
+
+/// Line indexes of the delimiters of any info block from an earlier run that only encloses header fields with the given
+/// keys, which are being generated again, so would be left enclosing nothing
+fn emptied_info_block_delimiters(lines: &[String], keys: &[&str]) -> Vec<usize> {
+    let mut delimiters: Vec<usize> = Vec::new();
+    let mut info_block_begin: Option<usize> = None;
+
+    for (index, line) in lines.iter().enumerate() {
+        if line == ANKERMAKE_INFO_BLOCK_BEGIN {
+            info_block_begin = Some(index);
+        } else if line == ANKERMAKE_INFO_BLOCK_END {
+            if let Some(begin) = info_block_begin.take() {
+                delimiters.extend([begin, index]);
+            }
+        } else if !is_regenerated_line(line, keys) {
+            info_block_begin = None;
+        }
+    }
+
+    delimiters
+}
+
+/// Whether the line is a header field from an earlier run with one of the given keys, which are being generated again,
+/// or the note that comes with one of them
+fn is_regenerated_line(line: &str, keys: &[&str]) -> bool {
+    match header_line_key(line) {
+        Some(key) => keys.contains(&key),
+        None => {
+            line == ANKERMAKE_ESTIMATED_WEIGHT_NOTE && keys.contains(&ANKERMAKE_FILAMENT_USED_G)
+        }
+    }
+}
+
+/// Create a new, empty file beside `file_path` to write its replacement into. Its name is unique to this process and
+/// call, and it is only created if nothing is there already, so that neither another conversion of the same file nor a
+/// file left behind by an interrupted one is ever written over.
+fn create_temporary_file(file_path: &Path) -> std::io::Result<(PathBuf, File)> {
+    static TEMPORARY_FILE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    loop {
+        let mut temporary_path_string: OsString = file_path.as_os_str().to_owned();
+        temporary_path_string.push(format!(
+            ".{}.{}.tmp",
+            std::process::id(),
+            TEMPORARY_FILE_COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let temporary_path: PathBuf = PathBuf::from(temporary_path_string);
+
+        match File::options()
+            .write(true)
+            .create_new(true)
+            .open(&temporary_path)
+        {
+            Ok(file) => return Ok((temporary_path, file)),
+            Err(file_creation_error) if file_creation_error.kind() == ErrorKind::AlreadyExists => {}
+            Err(file_creation_error) => return Err(file_creation_error),
+        }
+    }
+}
+
+/// A replacement for a file, written to a temporary file beside it that only takes its place once committed, so that an
+/// interrupted write never leaves a partially written file behind. Being in the same directory, the temporary file is
+/// always on the same filesystem as the file it replaces, SD card mounts included, so the rename can't fail for being
+/// across filesystems. Dropping it without committing removes the temporary file and leaves the original untouched.
+/// Writes go straight to the temporary file, so wrap it in a [`BufWriter`] for small writes.
+#[derive(Debug)]
+pub struct AtomicFile {
+    file_path: PathBuf,
+    temporary_path: PathBuf,
+    file: Option<File>,
+}
+
+impl AtomicFile {
+    /// Start writing a replacement for the file at `file_path`
+    pub fn create(file_path: &Path) -> std::io::Result<Self> {
+        let (temporary_path, file) = create_temporary_file(file_path)?;
+        Ok(AtomicFile {
+            file_path: file_path.to_path_buf(),
+            temporary_path,
+            file: Some(file),
+        })
+    }
+
+    /// Replace the file with everything written so far
+    pub fn commit(mut self) -> std::io::Result<()> {
+        if let Some(file) = self.file.take() {
+            file.sync_all()?;
+        }
+        std::fs::rename(&self.temporary_path, &self.file_path)?;
+        // Nothing is left to clean up once the rename has succeeded
+        self.temporary_path = PathBuf::new();
+        Ok(())
+    }
+
+    fn file(&mut self) -> &mut File {
+        self.file
+            .as_mut()
+            .expect("the file is only taken when committing, which consumes self")
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buffer: &[u8]) -> std::io::Result<usize> {
+        self.file().write(buffer)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file().flush()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        // Closed first, as an open file can't be removed on every platform
+        self.file.take();
+        if !self.temporary_path.as_os_str().is_empty() {
+            let _ = std::fs::remove_file(&self.temporary_path);
+        }
+    }
+}
+
+/// Replace the file at `file_path` with the contents, atomically. See [`AtomicFile`].
+pub fn write_file_atomically(file_path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut file: AtomicFile = AtomicFile::create(file_path)?;
+    file.write_all(contents)?;
+    file.commit()
+}
+
+/// Attempt to open the file at the location described in the string, logging the OS error if the file couldn't be opened for
+/// some reason. The converted file is written to `output_path_string` if given, otherwise over the original. A path of
+/// "-" reads from stdin or writes to stdout instead, and stdin is converted to stdout unless an output is given. Returns
+/// everything that should be printed about the file.
+pub fn process_file(
+    file_path_string: String,
+    output_path_string: Option<&str>,
+    options: &Options,
+) -> FileLog {
+    let file_path: &Path = Path::new(&file_path_string);
+
+    let mut reader: Box<dyn BufRead> = if file_path_string == STDIO_PATH {
+        Box::new(stdin().lock())
+    } else {
+        match File::open(file_path) {
+            Ok(file) => Box::new(BufReader::new(file)),
+            Err(file_opening_error) => {
+                let mut log: FileLog = FileLog::default();
+                log.diagnostics.push(Diagnostic::Io {
+                    action: "open",
+                    path: file_path_string.clone(),
+                    error: format!("{file_opening_error}"),
+                });
+                return log;
+            }
+        }
+    };
+
+    let read_failed = |file_reading_error: std::io::Error| -> FileLog {
+        let mut log: FileLog = FileLog::default();
+        log.diagnostics.push(Diagnostic::Io {
+            action: "read",
+            path: file_path_string.clone(),
+            error: format!("{file_reading_error}"),
+        });
+        log
+    };
+
+    // Binary gcode isn't text, so reading it line by line would mangle it
+    match is_binary_gcode(file_path, &mut reader) {
+        Ok(false) => {}
+        Ok(true) => {
+            let mut log: FileLog = FileLog::default();
+            log.diagnostics
+                .push(Diagnostic::BinaryGcode(file_path_string.clone()));
+            return log;
+        }
+        Err(file_reading_error) => return read_failed(file_reading_error),
+    }
+    let compression: Option<Compression> = match Compression::detect(file_path, &mut reader) {
+        Ok(compression) => compression,
+        Err(file_reading_error) => return read_failed(file_reading_error),
+    };
+
+    // Inspecting a file leaves it untouched, unless the converted file is explicitly wanted somewhere else as well
+    let converting: bool = !options.report_only
+        && !options.check
+        && (!options.inspect || output_path_string.is_some());
+
+    let output_path_string: &str = output_path_string.unwrap_or(&file_path_string);
+    let write_failed = |log: &mut FileLog, file_writing_error: std::io::Error| {
+        log.diagnostics.push(Diagnostic::Io {
+            action: "write",
+            path: output_path_string.to_string(),
+            error: format!("{file_writing_error}"),
+        });
+    };
+
+    if output_path_string == STDIO_PATH {
+        let (mut log, finished) = process_compressed_reader(
+            &file_path_string,
+            reader,
+            stdout().lock(),
+            compression,
+            converting,
+            options,
+        );
+        if let Err(file_writing_error) = finished {
+            write_failed(&mut log, file_writing_error);
+        }
+        return log;
+    }
+
+    // Only the converted gcode itself is written here, not when it's only being checked or its header is only wanted in
+    // a sidecar file
+    let writing: bool = converting && !options.header_sidecar;
+    if !writing || options.overwrite_policy == OverwritePolicy::Never {
+        let (log, _) = process_compressed_reader(
+            &file_path_string,
+            reader,
+            std::io::sink(),
+            compression,
+            converting,
+            options,
+        );
+        return log;
+    }
+
+    let output_path: &Path = Path::new(output_path_string);
+
+    // Buffered in full so that it can be compared with what's already there
+    if options.overwrite_policy == OverwritePolicy::IfChanged {
+        let (mut log, new_file_contents) = process_compressed_reader(
+            &file_path_string,
+            reader,
+            Vec::new(),
+            compression,
+            converting,
+            options,
+        );
+        let new_file_contents: Vec<u8> = match new_file_contents {
+            Ok(new_file_contents) => new_file_contents,
+            Err(file_writing_error) => {
+                write_failed(&mut log, file_writing_error);
+                return log;
+            }
+        };
+        let changed: bool = std::fs::read(output_path).map_or(true, |old_file_contents| {
+            old_file_contents != new_file_contents
+        });
+        if !log.has_errors() && changed {
+            if let Err(file_writing_error) = write_file_atomically(output_path, &new_file_contents)
+            {
+                write_failed(&mut log, file_writing_error);
+            }
+        }
+        return log;
+    }
+
+    // Otherwise streamed straight into the replacement, which is dropped if the file couldn't be converted
+    let output_file: AtomicFile = match AtomicFile::create(output_path) {
+        Ok(output_file) => output_file,
+        Err(file_writing_error) => {
+            let mut log: FileLog = FileLog::default();
+            write_failed(&mut log, file_writing_error);
+            return log;
+        }
+    };
+    let (mut log, output_file) = process_compressed_reader(
+        &file_path_string,
+        reader,
+        output_file,
+        compression,
+        converting,
+        options,
+    );
+    match output_file {
+        Ok(output_file) if !log.has_errors() => {
+            if let Err(file_writing_error) = output_file.commit() {
+                write_failed(&mut log, file_writing_error);
+            }
+        }
+        Ok(_) => {}
+        Err(file_writing_error) => write_failed(&mut log, file_writing_error),
+    }
+
+    log
+}
+
+/// Like [`process_reader`], but decompressing the gcode read from `reader` and compressing the converted file written to
+/// `writer` in the same way. Also returns `writer` once the compressed file has been finished off, so that it can be
+/// committed. A file compressed in a way this build can't read is skipped with an error.
+pub fn process_compressed_reader<R: BufRead, W: Write>(
+    file_path_string: &str,
+    reader: R,
+    mut writer: W,
+    compression: Option<Compression>,
+    converting: bool,
+    options: &Options,
+) -> (FileLog, std::io::Result<W>) {
+    match compression {
+        None => {
+            let log: FileLog =
+                process_reader(file_path_string, reader, &mut writer, converting, options);
+            (log, Ok(writer))
+        }
+        #[cfg(feature = "gzip")]
+        Some(Compression::Gzip) => {
+            let mut encoder = flate2::write::GzEncoder::new(writer, flate2::Compression::default());
+            let log: FileLog = process_reader(
+                file_path_string,
+                BufReader::new(flate2::bufread::MultiGzDecoder::new(reader)),
+                &mut encoder,
+                converting,
+                options,
+            );
+            (log, encoder.finish())
+        }
+        #[cfg(feature = "zstd")]
+        Some(Compression::Zstd) => {
+            let decoder = match zstd::stream::read::Decoder::with_buffer(reader) {
+                Ok(decoder) => decoder,
+                Err(file_reading_error) => {
+                    let mut log: FileLog = FileLog::default();
+                    log.diagnostics.push(Diagnostic::Io {
+                        action: "read",
+                        path: file_path_string.to_string(),
+                        error: format!("{file_reading_error}"),
+                    });
+                    return (log, Ok(writer));
+                }
+            };
+            // Level 0 is zstd's default level
+            let mut encoder = match zstd::stream::write::Encoder::new(writer, 0) {
+                Ok(encoder) => encoder,
+                Err(file_writing_error) => return (FileLog::default(), Err(file_writing_error)),
+            };
+            let log: FileLog = process_reader(
+                file_path_string,
+                BufReader::new(decoder),
+                &mut encoder,
+                converting,
+                options,
+            );
+            (log, encoder.finish())
+        }
+        // Only reachable for compressions whose feature isn't enabled
+        #[allow(unreachable_patterns)]
+        Some(compression) => {
+            let mut log: FileLog = FileLog::default();
+            log.diagnostics.push(Diagnostic::CompressedGcode {
+                path: file_path_string.to_string(),
+                compression,
+            });
+            (log, Ok(writer))
+        }
+    }
+}
+
+/// Read gcode from `reader`, writing the converted file to `writer` if `converting`, along with anything else the
+/// options ask for. `file_path_string` names the source in anything logged, and the trace and header sidecar files are
+/// written alongside it. Returns everything that should be printed about the file.
+pub fn process_reader<R: BufRead, W: Write>(
+    file_path_string: &str,
+    reader: R,
+    writer: W,
+    converting: bool,
+    options: &Options,
+) -> FileLog {
+    let mut log: FileLog = FileLog::default();
+    // Nothing is written if the file couldn't be read in full
+    let (lines, layout) = match read_lines(reader, options, &mut log) {
+        Ok(read) => read,
+        Err(ParsingError::Io(error)) => {
+            log.diagnostics.push(Diagnostic::Io {
+                action: "read",
+                path: file_path_string.to_string(),
+                error,
+            });
+            return log;
+        }
+        Err(parsing_error) => {
+            log.diagnostics.push(Diagnostic::Parsing {
+                path: file_path_string.to_string(),
+                error: parsing_error,
+            });
+            return log;
+        }
+    };
+    // Every pass below works from the same classification and the same extracted fields
+    let line_kinds: Vec<LineKind> = classify_lines(&lines);
+
+    if options.trace_gcode && !options.report_only && !options.check {
+        let trace_path_string: String = format!("{file_path_string}.{TRACE_EXTENSION}");
+        if let Err(file_writing_error) = write_file_atomically(
+            Path::new(&trace_path_string),
+            trace_lines(&line_kinds).as_bytes(),
+        ) {
+            log.diagnostics.push(Diagnostic::Io {
+                action: "write",
+                path: trace_path_string,
+                error: format!("{file_writing_error}"),
+            });
+        }
+    }
+
+    // Parsing errors are reported against the file, rather than crashing part way through a batch
+    let interesting_fields: Vec<InterestingFields> =
+        match extract_fields_or_fail(&line_kinds, options, &mut log) {
+            Ok(interesting_fields) => interesting_fields,
+            Err(parsing_error) => {
+                log.diagnostics.push(Diagnostic::Parsing {
+                    path: file_path_string.to_string(),
+                    error: parsing_error,
+                });
+                return log;
+            }
+        };
+
+    // The header is only needed to check or convert the file, and the warnings about the file that come with it are
+    // only wanted by those too. The check reports missing keys itself.
+    let header: Option<Header> = (options.check || converting).then(|| {
+        let mut header_log: FileLog = FileLog::default();
+        let header: Header =
+            generate_header(&line_kinds, &interesting_fields, options, &mut header_log);
+        log.diagnostics.extend(
+            header_log.diagnostics.into_iter().filter(|diagnostic| {
+                converting || !matches!(diagnostic, Diagnostic::MissingField(_))
+            }),
+        );
+        header
+    });
+
+    if options.inspect || options.report_only || options.verbose || options.csv.is_some() {
+        let summary: FileSummary =
+            FileSummary::from_lines(file_path_string, &line_kinds, interesting_fields, options);
+        if options.inspect {
+            log.stdout.push(summary.to_json());
+        }
+        if options.report_only {
+            log.stdout.push(summary.to_report_row());
+        }
+        if options.verbose {
+            log.stdout.push(summary.to_verbose_line());
+        }
+        log.summary = Some(summary);
+    }
+
+    let Some(header) = header else {
+        return log;
+    };
+
+    if options.check {
+        let missing: Vec<&'static str> = REQUIRED_HEADER_KEYS
+            .into_iter()
+            .filter(|key| !header.keys.contains(key))
+            .collect();
+        log.stdout.extend(REQUIRED_HEADER_KEYS.iter().map(|key| {
+            let status: &str = if missing.contains(key) {
+                "missing"
+            } else {
+                "present"
+            };
+            format!("{file_path_string}: {key}: {status}")
+        }));
+        if !missing.is_empty() {
+            log.diagnostics.push(Diagnostic::IncompleteHeader {
+                path: file_path_string.to_string(),
+                missing,
+            });
+        }
+    }
+
+    if !converting {
+        return log;
+    }
+
+    if options.header_sidecar {
+        let sidecar_path_string: String = format!("{file_path_string}.{HEADER_SIDECAR_EXTENSION}");
+        // Every line ends with the gcode file's line ending, so that the sidecar can be prepended to it as it is
+        let sidecar: String = header
+            .lines
+            .iter()
+            .map(|line| format!("{line}{}", layout.line_ending.as_str()))
+            .collect();
+        if let Err(file_writing_error) =
+            write_file_atomically(Path::new(&sidecar_path_string), sidecar.as_bytes())
+        {
+            log.diagnostics.push(Diagnostic::Io {
+                action: "write",
+                path: sidecar_path_string,
+                error: format!("{file_writing_error}"),
+            });
+        }
+        return log;
+    }
+
+    if let Err(file_writing_error) =
+        write_converted_lines(&lines, &line_kinds, &header, layout, options, writer)
+    {
+        log.diagnostics.push(Diagnostic::Io {
+            action: "write",
+            path: file_path_string.to_string(),
+            error: format!("{file_writing_error}"),
+        });
+    }
+
+    log
+}
+
+/// Check whether the file starts with a header written by this post-processor. Only the Ankermake header lines at the
+/// very top of the file are looked at, so a file that merely mentions the post-processor further down doesn't count.
+pub fn looks_converted(file_path: &Path) -> std::io::Result<bool> {
+    let post_processor_line: String = format!(";{ANKERMAKE_POST_PROCESSOR}:{POST_PROCESSOR_NAME} ");
+    let mut reader: BufReader<File> = BufReader::new(File::open(file_path)?);
+
+    while let Some((line, _)) = read_bounded_line(&mut reader, DEFAULT_MAX_LINE_LENGTH)? {
+        let line: &str = line.trim_end();
+        if line.starts_with(&post_processor_line) {
+            return Ok(true);
+        }
+        if ankermake_header_key(line).is_none()
+            && line != ANKERMAKE_INFO_BLOCK_BEGIN
+            && line != ANKERMAKE_INFO_BLOCK_END
+        {
+            break;
+        }
+    }
+
+    Ok(false)
+}
+
+/// Replace any directories among the paths with the gcode files inside them, in order of their paths so that the order
+/// is repeatable. Subdirectories are only searched if `recursive`, and symbolic links to directories are never followed.
+/// Files found this way that [`looks_converted`] are skipped. Anything skipped, and any directory that couldn't be read,
+/// is logged rather than stopping the search.
+pub fn expand_directories(paths: Vec<String>, recursive: bool, log: &mut FileLog) -> Vec<String> {
+    fn search(directory: &Path, recursive: bool, file_paths: &mut Vec<String>, log: &mut FileLog) {
+        let entries: std::io::Result<Vec<std::fs::DirEntry>> =
+            std::fs::read_dir(directory).and_then(|entries| entries.collect());
+        let mut entries: Vec<std::fs::DirEntry> = match entries {
+            Ok(entries) => entries,
+            Err(directory_reading_error) => {
+                log.diagnostics.push(Diagnostic::Io {
+                    action: "read",
+                    path: directory.display().to_string(),
+                    error: format!("{directory_reading_error}"),
+                });
+                return;
+            }
+        };
+        entries.sort_by_key(std::fs::DirEntry::path);
+
+        for entry in entries {
+            let path: std::path::PathBuf = entry.path();
+            if entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+                if recursive {
+                    search(&path, recursive, file_paths, log);
+                }
+                continue;
+            }
+
+            let is_gcode: bool = path.is_file()
+                && path
+                    .extension()
+                    .is_some_and(|extension| extension.eq_ignore_ascii_case(GCODE_EXTENSION));
+            if !is_gcode {
+                continue;
+            }
+            // A file that can't be read is left for process_file to report
+            if looks_converted(&path).unwrap_or(false) {
+                log.diagnostics
+                    .push(Diagnostic::AlreadyConverted(path.display().to_string()));
+            } else {
+                file_paths.push(path.display().to_string());
+            }
+        }
+    }
+
+    let mut file_paths: Vec<String> = Vec::new();
+    for path in paths {
+        if Path::new(&path).is_dir() {
+            search(Path::new(&path), recursive, &mut file_paths, log);
+        } else {
+            file_paths.push(path);
+        }
+    }
+    file_paths
+}
+
+/// What's left once every file in the batch has been processed and its log reported
+#[derive(Debug, Default)]
+pub struct BatchOutcome {
+    /// The summaries of the files, in the order the files were given
+    pub summaries: Vec<FileSummary>,
+    /// Number of files processed, whether or not they could be converted
+    pub files: usize,
+    /// Number of files that couldn't be converted
+    pub failed_files: usize,
+}
+
+impl BatchOutcome {
+    /// Keep what's needed from a file's log once it has been reported
+    pub fn record(&mut self, log: FileLog) {
+        self.files += 1;
+        if log.has_errors() {
+            self.failed_files += 1;
+        }
+        self.summaries.extend(log.summary);
+    }
+}
+
+/// Process every file, spreading them over `options.jobs` threads. Each file's log is passed to `report`, in the order the
+/// files were given, once that file and every file before it has finished. A file given more than once is only processed
+/// the first time, so that two threads never write to the same file.
+pub fn process_files(
+    mut file_paths: Vec<String>,
+    options: &Options,
+    mut report: impl FnMut(&FileLog) + Send,
+) -> BatchOutcome {
+    let mut seen_paths: Vec<std::path::PathBuf> = Vec::new();
+    file_paths.retain(|file_path| {
+        let path: std::path::PathBuf =
+            std::fs::canonicalize(file_path).unwrap_or_else(|_| file_path.into());
+        let first_time: bool = !seen_paths.contains(&path);
+        seen_paths.push(path);
+        first_time
+    });
+
+    if options.jobs <= 1 {
+        let mut outcome: BatchOutcome = BatchOutcome::default();
+        file_paths.into_iter().for_each(|file_path| {
+            let log: FileLog = process_file(file_path, options.output.as_deref(), options);
+            report(&log);
+            outcome.record(log);
+        });
+        return outcome;
+    }
+
+    let next_file: AtomicUsize = AtomicUsize::new(0);
+    let logs: Mutex<Vec<Option<FileLog>>> = Mutex::new(file_paths.iter().map(|_| None).collect());
+    // Only ever locked along with the next log to report, but needed for the threads to share it
+    let report: Mutex<_> = Mutex::new(report);
+    let next_to_report: Mutex<usize> = Mutex::new(0);
+    let outcome: Mutex<BatchOutcome> = Mutex::new(BatchOutcome::default());
+
+    thread::scope(|scope| {
+        for _ in 0..options.jobs.min(file_paths.len()) {
+            scope.spawn(|| loop {
+                let index: usize = next_file.fetch_add(1, Ordering::SeqCst);
+                let Some(file_path) = file_paths.get(index) else {
+                    break;
+                };
+
+                let log: FileLog =
+                    process_file(file_path.clone(), options.output.as_deref(), options);
+                logs.lock().unwrap()[index] = Some(log);
+
+                // Report every log that's now ready, without skipping past a file that is still being processed
+                let mut next_to_report = next_to_report.lock().unwrap();
+                loop {
+                    let ready_log: Option<FileLog> = logs
+                        .lock()
+                        .unwrap()
+                        .get_mut(*next_to_report)
+                        .and_then(Option::take);
+                    let Some(ready_log) = ready_log else {
+                        break;
+                    };
+
+                    (report.lock().unwrap())(&ready_log);
+                    outcome.lock().unwrap().record(ready_log);
+                    *next_to_report += 1;
+                }
+            });
+        }
+    });
+
+    outcome.into_inner().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    /// A small but complete Prusaslicer file, printing two layers of a single line each
+    const FIXTURE: &str = "\
+; generated by PrusaSlicer 2.6.0+linux-x64-GTK3 on 2023-06-27 at 10:00:00 UTC
+M83
+;LAYER_CHANGE
+;Z:0.2
+G1 Z0.2 F600
+G1 X10 Y10
+G1 X20 Y10 E1.5 F1200
+;LAYER_CHANGE
+;Z:0.4
+G1 Z0.4
+G1 X20 Y20 E1.5
+; filament used [mm] = 3.00
+; estimated printing time (normal mode) = 1h 2m 3s
+; prusaslicer_config = begin
+; gcode_flavor = marlin2
+; prusaslicer_config = end
+";
+
+    /// Three layers, with a Prusaslicer color change on the second and a bare M600 on the third
+    const COLOR_CHANGE_FIXTURE: &str = "\
+M83
+;LAYER_CHANGE
+;Z:0.2
+G1 X10 Y10 E2
+;LAYER_CHANGE
+;Z:0.4
+;COLOR_CHANGE,T0,#FF8000
+M600
+G1 X20 Y10 E3
+;LAYER_CHANGE
+;Z:0.6
+M600
+G1 X20 Y20 E1.5
+G1 E-0.5
+G1 E0.5
+";
+
+    /// Convert the gcode with the given options, returning the new file contents along with everything logged
+    fn convert(gcode: &str, options: &Options) -> (Result<String, ParsingError>, FileLog) {
+        let mut log: FileLog = FileLog::default();
+        let converted: Result<String, ParsingError> =
+            process_lines(gcode.as_bytes(), options, &mut log);
+        (converted, log)
+    }
+
+    /// An empty directory for a test to write files into, unique to the test
+    fn temporary_directory(test_name: &str) -> PathBuf {
+        let directory: PathBuf =
+            std::env::temp_dir().join(format!("prusa2anker-{}-{test_name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    /// The names of every file in the directory, in order
+    fn file_names(directory: &Path) -> Vec<String> {
+        let mut file_names: Vec<String> = std::fs::read_dir(directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        file_names.sort();
+        file_names
+    }
+
+    /// A reader that fails on every read, standing in for e.g. a disk error part way through a file
+    struct FailingReader;
+
+    impl Read for FailingReader {
+        fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("disk error"))
+        }
+    }
+
+    #[test]
+    fn classifies_representative_lines() {
+        assert_eq!(
+            LineKind::classify("; filament used [mm] = 1234.56"),
+            LineKind::Metadata("filament used [mm] = 1234.56")
+        );
+        assert_eq!(
+            LineKind::classify("  G1 X10 Y20 E0.5  "),
+            LineKind::Instruction("G1 X10 Y20 E0.5")
+        );
+        assert_eq!(
+            LineKind::classify(";LAYER_CHANGE"),
+            LineKind::Comment(";LAYER_CHANGE")
+        );
+        assert_eq!(LineKind::classify("   "), LineKind::Blank);
+        assert_eq!(LineKind::classify(""), LineKind::Blank);
+    }
+
+    #[test]
+    fn time_with_zero_padded_components() {
+        assert_eq!(
+            extract_time_data_as_seconds("estimated printing time (normal mode) = 02h 05m 09s"),
+            Ok(2 * 3600 + 5 * 60 + 9)
+        );
+        assert_eq!(
+            extract_time_data_as_seconds("estimated printing time (normal mode) = 00h 00m 05s"),
+            Ok(5)
+        );
+    }
+
+    #[test]
+    fn extremely_long_line_fails_the_file() {
+        let long_line: String = format!("; {}", "A".repeat(DEFAULT_MAX_LINE_LENGTH * 2));
+        let gcode: String = format!("G28\n{long_line}\nG1 X10\n");
+
+        let (converted, _) = convert(&gcode, &Options::default());
+        assert_eq!(
+            converted,
+            Err(ParsingError::LineTooLong {
+                line: 2,
+                max_line_length: DEFAULT_MAX_LINE_LENGTH
+            })
+        );
+    }
+
+    #[test]
+    fn extremely_long_line_is_truncated_when_lenient() {
+        let options: Options = Options {
+            max_line_length: SMALLEST_MAX_LINE_LENGTH,
+            lenient: true,
+            ..Options::default()
+        };
+        let long_line: String = format!("; {}", "A".repeat(DEFAULT_MAX_LINE_LENGTH * 2));
+        let gcode: String = format!("G28\n{long_line}\nG1 X10\n");
+
+        let (converted, log) = convert(&gcode, &options);
+        let converted: String = converted.unwrap();
+        assert!(converted.contains(&format!(
+            "\n{}\nG1 X10\n",
+            &long_line[..SMALLEST_MAX_LINE_LENGTH]
+        )));
+        assert!(log.diagnostics.contains(&Diagnostic::LineTruncated {
+            line: 2,
+            max_line_length: SMALLEST_MAX_LINE_LENGTH
+        }));
+    }
+
+    #[test]
+    fn invalid_utf8_is_replaced_rather_than_dropping_the_rest_of_the_file() {
+        let mut log: FileLog = FileLog::default();
+        let gcode: &[u8] = b"G28\n; object \xe9t\xe9\nG1 X10\n";
+
+        let (lines, _) = read_lines(gcode, &Options::default(), &mut log).unwrap();
+        assert_eq!(lines, ["G28", "; object \u{fffd}t\u{fffd}", "G1 X10"]);
+    }
+
+    #[test]
+    fn read_error_leaves_the_file_unwritten() {
+        let reader = BufReader::new(b"G28\nG1 X10\n".chain(FailingReader));
+        let mut new_file_contents: Vec<u8> = Vec::new();
+
+        let log: FileLog = process_reader(
+            "print.gcode",
+            reader,
+            &mut new_file_contents,
+            true,
+            &Options::default(),
+        );
+        assert!(new_file_contents.is_empty());
+        assert!(log.has_errors());
+        // The OS's own message is kept as it is, rather than the debug form of the error
+        assert_eq!(
+            log.diagnostics,
+            [Diagnostic::Io {
+                action: "read",
+                path: "print.gcode".to_string(),
+                error: "disk error".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn eu_number_format_groups_and_uses_a_decimal_comma_for_filament() {
+        let options: Options = Options {
+            number_format: NumberFormat::Eu,
+            ..Options::default()
+        };
+        assert_eq!(
+            InterestingFields::FilamentUsed(123456789).to_header_line(&options),
+            ";Filament used: 1.234,56789m"
+        );
+
+        let options: Options = Options {
+            filament_unit: FilamentUnit::Millimeters,
+            ..options
+        };
+        assert_eq!(
+            InterestingFields::FilamentUsed(123456789).to_header_line(&options),
+            ";Filament used: 1.234.567,89mm"
+        );
+    }
+
+    #[test]
+    fn eu_number_format_leaves_integer_fields_ungrouped() {
+        let options: Options = Options {
+            number_format: NumberFormat::Eu,
+            ..Options::default()
+        };
+        assert_eq!(
+            InterestingFields::Time(12345).to_header_line(&options),
+            ";TIME:12345"
+        );
+        assert_eq!(
+            InterestingFields::LayerCount(1234).to_header_line(&options),
+            ";LAYER_COUNT:1234"
+        );
+    }
+
+    #[test]
+    fn atomic_write_replaces_the_target_without_leaving_temporary_files() {
+        let directory: PathBuf = temporary_directory("atomic-write");
+        let file_path: PathBuf = directory.join("print.gcode");
+        std::fs::write(&file_path, "G28\n").unwrap();
+
+        write_file_atomically(&file_path, b"G28\nG1 X10\n").unwrap();
+        write_file_atomically(&file_path, b"G28\nG1 X20\n").unwrap();
+        assert_eq!(std::fs::read(&file_path).unwrap(), b"G28\nG1 X20\n");
+        assert_eq!(file_names(&directory), ["print.gcode"]);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn atomic_write_failure_leaves_the_target_alone() {
+        let directory: PathBuf = temporary_directory("atomic-write-failure");
+        // A directory can't be renamed over by a file
+        let file_path: PathBuf = directory.join("print.gcode");
+        std::fs::create_dir(&file_path).unwrap();
+
+        assert!(write_file_atomically(&file_path, b"G28\n").is_err());
+        assert!(file_path.is_dir());
+        assert_eq!(file_names(&directory), ["print.gcode"]);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn streamed_conversion_of_a_large_file_matches_converting_in_memory() {
+        let directory: PathBuf = temporary_directory("large-file");
+        let file_path: PathBuf = directory.join("print.gcode");
+        let mut gcode: String = "M83\n".to_string();
+        for layer in 1..=1000 {
+            gcode.push_str(&format!(";LAYER_CHANGE\n;Z:{}\n", layer as f64 * 0.2));
+            for point in 0..100 {
+                gcode.push_str(&format!("G1 X{point} Y{layer} E0.01\n"));
+            }
+        }
+        gcode.push_str("; filament used [mm] = 2000.00\n");
+        gcode.push_str("; estimated printing time (normal mode) = 10h 0m 0s\n");
+        std::fs::write(&file_path, &gcode).unwrap();
+
+        let log: FileLog = process_file(file_path.display().to_string(), None, &Options::default());
+        assert!(!log.has_errors());
+
+        let (expected, _) = convert(&gcode, &Options::default());
+        assert_eq!(
+            std::fs::read_to_string(&file_path).unwrap(),
+            expected.unwrap()
+        );
+        assert_eq!(file_names(&directory), ["print.gcode"]);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn failed_conversion_leaves_the_file_untouched() {
+        let directory: PathBuf = temporary_directory("failed-conversion");
+        let file_path: PathBuf = directory.join("print.gcode");
+        let gcode: &str = "G28\n; estimated printing time (normal mode) = 1x\n";
+        std::fs::write(&file_path, gcode).unwrap();
+
+        let log: FileLog = process_file(file_path.display().to_string(), None, &Options::default());
+        assert!(log.has_errors());
+        assert_eq!(std::fs::read_to_string(&file_path).unwrap(), gcode);
+        assert_eq!(file_names(&directory), ["print.gcode"]);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn verbose_summaries_are_reported_in_order() {
+        let directory: PathBuf = temporary_directory("verbose");
+        let file_paths: Vec<String> = ["a.gcode", "b.gcode", "c.gcode"]
+            .iter()
+            .map(|file_name| {
+                let file_path: PathBuf = directory.join(file_name);
+                std::fs::write(&file_path, FIXTURE).unwrap();
+                file_path.display().to_string()
+            })
+            .collect();
+        let options: Options = Options {
+            verbose: true,
+            jobs: 2,
+            ..Options::default()
+        };
+
+        let mut reported: Vec<String> = Vec::new();
+        let outcome: BatchOutcome = process_files(file_paths.clone(), &options, |log| {
+            reported.extend(log.stdout.iter().cloned())
+        });
+        assert_eq!(outcome.files, 3);
+        assert_eq!(outcome.failed_files, 0);
+        let expected: Vec<String> = file_paths
+            .iter()
+            .map(|file_path| {
+                format!(
+                    "{file_path}: time 1h 02m 03s, filament 0.00300m, flavour Marlin, found FLAVOR, PostProcessor, \
+                    TIME, Filament used, LAYER_COUNT, MINX, MAXX, MINY, MAXY, MINZ, MAXZ, missing none"
+                )
+            })
+            .collect();
+        assert_eq!(reported, expected);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn csv_summary_has_a_row_per_file() {
+        let directory: PathBuf = temporary_directory("csv");
+        let csv_path: PathBuf = directory.join("summary.csv");
+        let file_paths: Vec<String> = [
+            ("a.gcode", FIXTURE),
+            ("b.gcode", &FIXTURE.replace("1h 2m 3s", "2m")),
+        ]
+        .iter()
+        .map(|(file_name, gcode)| {
+            let file_path: PathBuf = directory.join(file_name);
+            std::fs::write(&file_path, gcode).unwrap();
+            file_path.display().to_string()
+        })
+        .collect();
+        let options: Options = Options {
+            csv: Some(csv_path.display().to_string()),
+            ..Options::default()
+        };
+
+        let outcome: BatchOutcome = process_files(file_paths.clone(), &options, |_| {});
+        write_csv_summary(options.csv.as_deref().unwrap(), &outcome.summaries).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&csv_path).unwrap(),
+            format!(
+                "{CSV_HEADER}\n{},3723,0.00300,2,Marlin,0\n{},120,0.00300,2,Marlin,0\n",
+                file_paths[0], file_paths[1]
+            )
+        );
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    /// The value of the header line for the key, if there is one
+    fn header_value<'a>(converted: &'a str, key: &str) -> Option<&'a str> {
+        converted
+            .lines()
+            .find_map(|line| line.strip_prefix(&format!(";{key}:")))
+    }
+
+    #[test]
+    fn max_z_is_taken_from_the_config_when_it_records_it() {
+        let gcode: String = FIXTURE.replace(
+            "; gcode_flavor = marlin2",
+            "; gcode_flavor = marlin2\n; max_layer_z = 0.45",
+        );
+
+        let (converted, _) = convert(&gcode, &Options::default());
+        assert_eq!(
+            header_value(&converted.unwrap(), ANKERMAKE_MAX_Z),
+            Some("0.45")
+        );
+    }
+
+    #[test]
+    fn max_z_falls_back_to_the_highest_extruding_move() {
+        let (converted, _) = convert(FIXTURE, &Options::default());
+        assert_eq!(
+            header_value(&converted.unwrap(), ANKERMAKE_MAX_Z),
+            Some("0.40")
+        );
+    }
+
+    #[test]
+    fn fast_mode_still_produces_the_core_header_fields() {
+        let options: Options = Options {
+            fast: true,
+            ..Options::default()
+        };
+
+        let (converted, _) = convert(FIXTURE, &options);
+        let converted: String = converted.unwrap();
+        assert_eq!(
+            header_value(&converted, ANKERMAKE_PRINTING_TIME),
+            Some("3723")
+        );
+        assert_eq!(
+            header_value(&converted, ANKERMAKE_FILAMENT_USED_M),
+            Some(" 0.00300m")
+        );
+        assert_eq!(header_value(&converted, ANKERMAKE_FLAVOUR), Some("Marlin"));
+        assert_eq!(header_value(&converted, ANKERMAKE_LAYER_COUNT), Some("2"));
+        assert_eq!(header_value(&converted, ANKERMAKE_MIN_X), None);
+    }
+
+    #[test]
+    fn temperatures_are_taken_from_the_first_extruder() {
+        let gcode: String = FIXTURE.replace(
+            "; gcode_flavor = marlin2",
+            "\
+; bed_temperature = 60,65
+; first_layer_bed_temperature = 70,70
+; first_layer_temperature = 220,225
+; gcode_flavor = marlin2
+; temperature = 215,210",
+        );
+
+        let (converted, _) = convert(&gcode, &Options::default());
+        let converted: String = converted.unwrap();
+        assert_eq!(
+            header_value(&converted, ANKERMAKE_BED_TEMPERATURE),
+            Some("60")
+        );
+        assert_eq!(
+            header_value(&converted, ANKERMAKE_HOTEND_TEMPERATURE),
+            Some("215")
+        );
+    }
+
+    #[test]
+    fn temperatures_default_to_the_filament_types() {
+        let gcode: String = FIXTURE.replace(
+            "; gcode_flavor = marlin2",
+            "; filament_type = PETG;PLA\n; gcode_flavor = marlin2",
+        );
+
+        let (converted, log) = convert(&gcode, &Options::default());
+        let converted: String = converted.unwrap();
+        assert_eq!(
+            header_value(&converted, ANKERMAKE_BED_TEMPERATURE),
+            Some("85")
+        );
+        assert_eq!(
+            header_value(&converted, ANKERMAKE_HOTEND_TEMPERATURE),
+            Some("240")
+        );
+        assert!(log.diagnostics.contains(&Diagnostic::DefaultTemperatures));
+    }
+
+    #[test]
+    fn temperatures_in_the_config_win_over_the_filament_types() {
+        let gcode: String = FIXTURE.replace(
+            "; gcode_flavor = marlin2",
+            "; bed_temperature = 65\n; filament_type = PLA\n; gcode_flavor = marlin2",
+        );
+
+        let (converted, log) = convert(&gcode, &Options::default());
+        let converted: String = converted.unwrap();
+        assert_eq!(
+            header_value(&converted, ANKERMAKE_BED_TEMPERATURE),
+            Some("65")
+        );
+        assert_eq!(
+            header_value(&converted, ANKERMAKE_HOTEND_TEMPERATURE),
+            Some("210")
+        );
+        assert!(log.diagnostics.contains(&Diagnostic::DefaultTemperatures));
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn gzip_compressed_file_is_converted_and_compressed_again() {
+        let directory: PathBuf = temporary_directory("gzip");
+        let file_path: PathBuf = directory.join("print.gcode.gz");
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(FIXTURE.as_bytes()).unwrap();
+        std::fs::write(&file_path, encoder.finish().unwrap()).unwrap();
+
+        let log: FileLog = process_file(file_path.display().to_string(), None, &Options::default());
+        assert!(!log.has_errors());
+
+        let mut converted: String = String::new();
+        flate2::read::GzDecoder::new(File::open(&file_path).unwrap())
+            .read_to_string(&mut converted)
+            .unwrap();
+        let (expected, _) = convert(FIXTURE, &Options::default());
+        assert_eq!(converted, expected.unwrap());
+        assert_eq!(file_names(&directory), ["print.gcode.gz"]);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[cfg(not(feature = "gzip"))]
+    #[test]
+    fn gzip_compressed_file_is_skipped_without_the_feature() {
+        let directory: PathBuf = temporary_directory("gzip-skipped");
+        let file_path: PathBuf = directory.join("print.gcode.gz");
+        std::fs::write(&file_path, [GZIP_MAGIC, b"not really gzip"].concat()).unwrap();
+
+        let log: FileLog = process_file(file_path.display().to_string(), None, &Options::default());
+        assert_eq!(
+            log.diagnostics,
+            [Diagnostic::CompressedGcode {
+                path: file_path.display().to_string(),
+                compression: Compression::Gzip
+            }]
+        );
+        assert_eq!(
+            std::fs::read(&file_path).unwrap(),
+            [GZIP_MAGIC, b"not really gzip"].concat()
+        );
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_compressed_file_is_converted_and_compressed_again() {
+        let directory: PathBuf = temporary_directory("zstd");
+        let file_path: PathBuf = directory.join("print.gcode.zst");
+        std::fs::write(&file_path, zstd::encode_all(FIXTURE.as_bytes(), 0).unwrap()).unwrap();
+
+        let log: FileLog = process_file(file_path.display().to_string(), None, &Options::default());
+        assert!(!log.has_errors());
+
+        let converted: Vec<u8> = zstd::decode_all(File::open(&file_path).unwrap()).unwrap();
+        let (expected, _) = convert(FIXTURE, &Options::default());
+        assert_eq!(String::from_utf8(converted).unwrap(), expected.unwrap());
+        assert_eq!(file_names(&directory), ["print.gcode.zst"]);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn filament_unit_is_applied_to_the_same_value() {
+        let field: InterestingFields = InterestingFields::FilamentUsed(123456);
+        assert_eq!(
+            field.to_header_line(&Options::default()),
+            ";Filament used: 1.23456m"
+        );
+        let options: Options = Options {
+            filament_unit: FilamentUnit::Millimeters,
+            ..Options::default()
+        };
+        assert_eq!(field.to_header_line(&options), ";Filament used: 1234.56mm");
+    }
+
+    #[test]
+    fn filament_length_with_several_decimal_points_is_rejected() {
+        assert_eq!(
+            extract_filament_used_as_um_x10("filament used [mm] = 12.34.56"),
+            Err(ParsingError::StringParsingError(
+                "u64",
+                "12.34.56".to_string()
+            ))
+        );
+
+        let (converted, _) = convert(
+            &FIXTURE.replace("= 3.00", "= 12.34.56"),
+            &Options::default(),
+        );
+        assert!(matches!(converted, Err(ParsingError::InvalidLines(_))));
+    }
+
+    #[test]
+    fn time_followed_by_a_note_in_parentheses() {
+        assert_eq!(
+            extract_time_data_as_seconds(
+                "estimated printing time (normal mode) = 2h 3m (silent 2h 30m)"
+            ),
+            Ok(2 * 3600 + 3 * 60)
+        );
+
+        let (converted, _) = convert(
+            &FIXTURE.replace("1h 2m 3s", "1h 2m 3s (incl. 5m for the purge)"),
+            &Options::default(),
+        );
+        assert_eq!(header_value(&converted.unwrap(), "TIME"), Some("3723"));
+    }
+
+    #[test]
+    fn metadata_with_unusual_spacing_around_the_semicolon() {
+        assert_eq!(
+            LineKind::classify("  ; estimated printing time (normal mode) = 1h 2m 3s"),
+            LineKind::Metadata("estimated printing time (normal mode) = 1h 2m 3s")
+        );
+        assert_eq!(
+            LineKind::classify(";  filament used [mm] = 3.00"),
+            LineKind::Metadata("filament used [mm] = 3.00")
+        );
+
+        let gcode: String = FIXTURE
+            .replace("; estimated printing time", "  ; estimated printing time")
+            .replace("; filament used", ";  filament used");
+        let (converted, _) = convert(&gcode, &Options::default());
+        let converted: String = converted.unwrap();
+        assert_eq!(header_value(&converted, "TIME"), Some("3723"));
+        assert_eq!(header_value(&converted, "Filament used"), Some(" 0.00300m"));
+    }
+
+    #[test]
+    fn header_lines_are_in_a_fixed_order() {
+        let gcode: String = FIXTURE
+            .replace(
+                "; gcode_flavor = marlin2",
+                "; gcode_flavor = marlin2\n; bed_temperature = 60\n; temperature = 215",
+            )
+            .replacen(
+                "M83",
+                "; thumbnail begin 16x16 12\n; iVBORw0KGgo=\n; thumbnail end\nM83",
+                1,
+            );
+
+        let (converted, _) = convert(&gcode, &Options::default());
+        let header: Vec<String> = converted
+            .unwrap()
+            .lines()
+            .take_while(|line| ankermake_header_key(line).is_some())
+            .map(String::from)
+            .collect();
+        assert_eq!(
+            header,
+            [
+                ";FLAVOR:Marlin".to_string(),
+                format!(";PostProcessor:{POST_PROCESSOR_NAME} {}", version()),
+                ";TIME:3723".to_string(),
+                ";Filament used: 0.00300m".to_string(),
+                ";BEDTEMP:60".to_string(),
+                ";HOTENDTEMP:215".to_string(),
+                ";LAYER_COUNT:2".to_string(),
+                ";MINX:10.00".to_string(),
+                ";MAXX:20.00".to_string(),
+                ";MINY:10.00".to_string(),
+                ";MAXY:20.00".to_string(),
+                ";MINZ:0.20".to_string(),
+                ";MAXZ:0.40".to_string(),
+                format!(";{ANKERMAKE_LARGE_THUMBNAIL}:iVBORw0KGgo="),
+                format!(";{ANKERMAKE_SMALL_THUMBNAIL}:iVBORw0KGgo="),
+            ]
+        );
+    }
+
+    #[test]
+    fn instructions_with_tabs_and_repeated_spaces() {
+        assert_eq!(gcode_command("G1\tX10  Y20"), "G1");
+        assert_eq!(gcode_parameter("G1\tX10  Y20", 'X'), Some(10.0));
+        assert_eq!(gcode_parameter("G1\tX10  Y20", 'Y'), Some(20.0));
+        assert_eq!(gcode_parameter("G1 \t X10\t\tE0.5 ; wipe", 'E'), Some(0.5));
+        assert_eq!(
+            LineKind::classify("\tG1\tX10  Y20 "),
+            LineKind::Instruction("G1\tX10  Y20")
+        );
+    }
+
+    #[test]
+    fn time_with_unexpected_tokens_is_an_error() {
+        assert_eq!(
+            extract_time_data_as_seconds("estimated printing time (normal mode) = 1h 5x"),
+            Err(ParsingError::StringParsingError(
+                "duration",
+                "5x".to_string()
+            ))
+        );
+        assert_eq!(
+            extract_time_data_as_seconds("estimated printing time (normal mode) = 1h m"),
+            Err(ParsingError::StringParsingError("u64", "m".to_string()))
+        );
+        assert_eq!(
+            extract_time_data_as_seconds("estimated printing time (normal mode)"),
+            Err(ParsingError::MissingValue(
+                "estimated printing time (normal mode)".to_string()
+            ))
+        );
+        // Doubled spaces leave empty tokens, which aren't an error
+        assert_eq!(
+            extract_time_data_as_seconds("estimated printing time (normal mode) = 1h  3s"),
+            Ok(3603)
+        );
+    }
+
+    #[test]
+    fn time_spanning_several_days() {
+        assert_eq!(
+            extract_time_data_as_seconds("estimated printing time (normal mode) = 1d 3h 20m 15s"),
+            Ok(98415)
+        );
+        assert_eq!(
+            extract_time_data_as_seconds("estimated printing time (normal mode) = 2d 0h 0m 0s"),
+            Ok(2 * 24 * 3600)
+        );
+    }
+
+    #[test]
+    fn filament_lengths_with_fewer_decimal_places() {
+        assert_eq!(parse_length_mm_as_um_x10("1200"), Ok(120000));
+        assert_eq!(parse_length_mm_as_um_x10("1200.5"), Ok(120050));
+        assert_eq!(parse_length_mm_as_um_x10("1200.55"), Ok(120055));
+        assert_eq!(
+            extract_filament_used_as_um_x10("filament used [mm] = 1200"),
+            Ok(120000)
+        );
+    }
+
+    #[test]
+    fn filament_lengths_of_every_extruder_are_summed() {
+        assert_eq!(
+            extract_filament_used_as_um_x10("filament used [mm] = 123.45, 678.90"),
+            Ok(80235)
+        );
+        assert_eq!(
+            extract_filament_used_as_um_x10("filament used [mm] = 123.45, 678.90, 0.00"),
+            Ok(80235)
+        );
+        assert_eq!(
+            extract_filament_used_as_um_x10("filament used [mm] = 1.5,2.5,3"),
+            Ok(700)
+        );
+    }
+
+    #[test]
+    fn converting_an_already_converted_file_changes_nothing() {
+        for options in [
+            Options::default(),
+            Options {
+                info_block: true,
+                ..Options::default()
+            },
+        ] {
+            let (converted_once, _) = convert(FIXTURE, &options);
+            let converted_once: String = converted_once.unwrap();
+            let (converted_twice, _) = convert(&converted_once, &options);
+            assert_eq!(converted_twice.unwrap(), converted_once);
+        }
+    }
+
+    #[test]
+    fn thousands_separators_are_told_apart_from_lists() {
+        assert!(is_thousands_separated("1,234.56"));
+        assert!(is_thousands_separated("12,345,678"));
+        assert!(!is_thousands_separated("12.3,45.6"));
+        assert!(!is_thousands_separated("1234.56"));
+        assert_eq!(
+            extract_filament_used_as_um_x10("filament used [mm] = 1,234.56"),
+            Ok(123456)
+        );
+        assert_eq!(
+            extract_filament_used_as_um_x10("filament used [mm] = 12.3,45.6"),
+            Ok(5790)
+        );
+    }
+
+    #[test]
+    fn crlf_line_endings_are_kept() {
+        let (converted, _) = convert(&FIXTURE.replace('\n', "\r\n"), &Options::default());
+        let converted: String = converted.unwrap();
+        let (expected, _) = convert(FIXTURE, &Options::default());
+        assert_eq!(converted, expected.unwrap().replace('\n', "\r\n"));
+        assert_eq!(header_value(&converted, "TIME"), Some("3723"));
+    }
+
+    #[test]
+    fn trailing_newline_is_kept_only_when_the_file_has_one() {
+        let (converted, _) = convert(FIXTURE, &Options::default());
+        let with_newline: String = converted.unwrap();
+        assert!(with_newline.ends_with("; prusaslicer_config = end\n"));
+
+        let (converted, _) = convert(FIXTURE.trim_end(), &Options::default());
+        let without_newline: String = converted.unwrap();
+        assert!(without_newline.ends_with("; prusaslicer_config = end"));
+        assert_eq!(format!("{without_newline}\n"), with_newline);
+
+        // An empty file has no lines to follow the header
+        let (converted, _) = convert("", &Options::default());
+        assert_eq!(
+            converted.unwrap(),
+            format!(
+                ";FLAVOR:Marlin\n;PostProcessor:{POST_PROCESSOR_NAME} {}",
+                version()
+            )
+        );
+    }
+
+    #[test]
+    fn bounding_box_of_a_square_path() {
+        let lines: Vec<String> = [
+            "G28",
+            "M83",
+            "G1 X5 Y5 Z50 F9000 ; travel well outside the print",
+            "G1 X10 Y10 Z0.3",
+            "G1 X30 Y10 E1",
+            "G1 X30 Y40 E1",
+            "G1 X10 Y40 E1",
+            "G1 X10 Y10 E1",
+            "G91",
+            "G1 Z0.2",
+            "G1 X20 E1",
+            "G90",
+            "G1 X200 Y200",
+        ]
+        .map(String::from)
+        .to_vec();
+        let bounding_box: BoundingBox = BoundingBox::from_lines(&classify_lines(&lines)).unwrap();
+        assert_eq!(
+            bounding_box,
+            BoundingBox {
+                min: [10.0, 10.0, 0.3],
+                max: [30.0, 40.0, 0.5],
+            }
+        );
+
+        let header_lines: Vec<String> = bounding_box
+            .to_fields(None)
+            .iter()
+            .map(|field| field.to_header_line(&Options::default()))
+            .collect();
+        assert_eq!(
+            header_lines,
+            [
+                ";MINX:10.00",
+                ";MAXX:30.00",
+                ";MINY:10.00",
+                ";MAXY:40.00",
+                ";MINZ:0.30",
+                ";MAXZ:0.50"
+            ]
+        );
+    }
+
+    #[test]
+    fn extruded_filament_follows_relative_and_absolute_extrusion() {
+        let total = |gcode: &[&str]| -> f64 {
+            let lines: Vec<String> = gcode.iter().map(|line| line.to_string()).collect();
+            total_extruded_mm(&classify_lines(&lines))
+        };
+
+        assert_eq!(total(&["M83", "G1 X10 E1.5", "G1 X20 E2.5"]), 4.0);
+        assert_eq!(total(&["M82", "G1 X10 E1.5", "G1 X20 E4"]), 4.0);
+        // Retractions cancel out the matching unretraction
+        assert_eq!(
+            total(&["M83", "G1 X10 E2", "G1 E-0.8", "G1 E0.8", "G1 X20 E2"]),
+            4.0
+        );
+        // Resetting the extruder position doesn't count as extruding
+        assert_eq!(
+            total(&[
+                "M82",
+                "G1 X10 E3",
+                "G92 E0",
+                "G1 X20 E1",
+                "M83",
+                "G1 X30 E1"
+            ]),
+            5.0
+        );
+    }
+
+    #[test]
+    fn layer_count_counts_every_layer_change() {
+        let mut gcode: String = "M83\n".to_string();
+        for layer in 1..=5 {
+            gcode.push_str(&format!(
+                ";LAYER_CHANGE\n;Z:{}\nG1 X{layer} Y10 E0.5\n",
+                layer as f64 * 0.2
+            ));
+        }
+
+        let (converted, _) = convert(&gcode, &Options::default());
+        assert_eq!(header_value(&converted.unwrap(), "LAYER_COUNT"), Some("5"));
+    }
+
+    #[test]
+    fn thumbnails_are_round_tripped_into_the_header() {
+        // The payloads are base64 encoded PNG signatures, which are all that's checked
+        let gcode: String = FIXTURE.replacen(
+            "M83",
+            "; thumbnail begin 16x16 12\n; iVBORw0KGgo=\n; thumbnail end\n\
+            ; thumbnail begin 220x124 16\n; iVBORw0K\n; GgoAAAAN\n; thumbnail end\nM83",
+            1,
+        );
+
+        let (converted, _) = convert(&gcode, &Options::default());
+        let converted: String = converted.unwrap();
+        assert_eq!(
+            header_value(&converted, ANKERMAKE_LARGE_THUMBNAIL),
+            Some("iVBORw0KGgoAAAAN")
+        );
+        assert_eq!(
+            header_value(&converted, ANKERMAKE_SMALL_THUMBNAIL),
+            Some("iVBORw0KGgo=")
+        );
+
+        // Anything that doesn't decode to a PNG is left out
+        let (converted, _) = convert(
+            &FIXTURE.replacen(
+                "M83",
+                "; thumbnail begin 16x16 4\n; QUJD\n; thumbnail end\nM83",
+                1,
+            ),
+            &Options::default(),
+        );
+        assert_eq!(
+            header_value(&converted.unwrap(), ANKERMAKE_LARGE_THUMBNAIL),
+            None
+        );
+    }
+
+    #[test]
+    fn every_broken_line_is_reported() {
+        let gcode: String = FIXTURE
+            .replace("= 3.00", "= 3.0.0")
+            .replace("1h 2m 3s", "1h 2q");
+
+        let (converted, _) = convert(&gcode, &Options::default());
+        assert_eq!(
+            converted,
+            Err(ParsingError::InvalidLines(vec![
+                (
+                    12,
+                    ParsingError::StringParsingError("u64", "3.0.0".to_string())
+                ),
+                (
+                    13,
+                    ParsingError::StringParsingError("duration", "2q".to_string())
+                ),
+            ]))
+        );
+    }
+
+    #[test]
+    fn parsing_errors_describe_themselves() {
+        assert_eq!(
+            ParsingError::MissingValue("filament used [mm]".to_string()).to_string(),
+            "no value found in \"filament used [mm]\""
+        );
+        assert_eq!(
+            ParsingError::StringParsingError("u64", "3.0.0".to_string()).to_string(),
+            "\"3.0.0\" is not a valid u64"
+        );
+        assert_eq!(
+            ParsingError::Io("disk error".to_string()).to_string(),
+            "failed to read or write the gcode: disk error"
+        );
+        assert_eq!(
+            ParsingError::LineTooLong {
+                line: 2,
+                max_line_length: 1024
+            }
+            .to_string(),
+            "line 2 is longer than 1024 bytes, raise the maximum line length or pass --lenient to truncate it"
+        );
+        assert_eq!(
+            ParsingError::InvalidLines(vec![
+                (12, ParsingError::MissingValue("a".to_string())),
+                (
+                    13,
+                    ParsingError::StringParsingError("duration", "2q".to_string())
+                ),
+            ])
+            .to_string(),
+            "line 12: no value found in \"a\"; line 13: \"2q\" is not a valid duration"
+        );
+
+        let error: Box<dyn std::error::Error> =
+            Box::new(ParsingError::MissingValue("a".to_string()));
+        assert_eq!(error.to_string(), "no value found in \"a\"");
+    }
+
+    #[test]
+    fn check_reports_missing_fields_without_touching_the_file() {
+        let directory: PathBuf = temporary_directory("check");
+        let complete_path: PathBuf = directory.join("complete.gcode");
+        let incomplete_path: PathBuf = directory.join("incomplete.gcode");
+        let incomplete: String =
+            FIXTURE.replace("; estimated printing time (normal mode) = 1h 2m 3s\n", "");
+        std::fs::write(&complete_path, FIXTURE).unwrap();
+        std::fs::write(&incomplete_path, &incomplete).unwrap();
+        let options: Options = Options {
+            check: true,
+            ..Options::default()
+        };
+
+        let complete_path_string: String = complete_path.display().to_string();
+        let log: FileLog = process_file(complete_path_string.clone(), None, &options);
+        assert!(!log.has_errors());
+        assert_eq!(
+            log.stdout,
+            REQUIRED_HEADER_KEYS.map(|key| format!("{complete_path_string}: {key}: present"))
+        );
+
+        let incomplete_path_string: String = incomplete_path.display().to_string();
+        let log: FileLog = process_file(incomplete_path_string.clone(), None, &options);
+        assert!(log.has_errors());
+        assert!(log
+            .stdout
+            .contains(&format!("{incomplete_path_string}: TIME: missing")));
+        assert!(log.diagnostics.contains(&Diagnostic::IncompleteHeader {
+            path: incomplete_path_string,
+            missing: vec![ANKERMAKE_PRINTING_TIME],
+        }));
+
+        assert_eq!(std::fs::read_to_string(&complete_path).unwrap(), FIXTURE);
+        assert_eq!(
+            std::fs::read_to_string(&incomplete_path).unwrap(),
+            incomplete
+        );
+        assert_eq!(
+            file_names(&directory),
+            ["complete.gcode", "incomplete.gcode"]
+        );
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn files_converted_in_parallel_match_converting_them_one_at_a_time() {
+        let directory: PathBuf = temporary_directory("parallel");
+        let gcodes: Vec<String> = (1..=8)
+            .map(|minutes| FIXTURE.replace("1h 2m 3s", &format!("{minutes}m")))
+            .collect();
+        let file_paths: Vec<String> = gcodes
+            .iter()
+            .enumerate()
+            .map(|(index, gcode)| {
+                let file_path: PathBuf = directory.join(format!("{index}.gcode"));
+                std::fs::write(&file_path, gcode).unwrap();
+                file_path.display().to_string()
+            })
+            .collect();
+        let options: Options = Options {
+            jobs: 4,
+            ..Options::default()
+        };
+
+        let outcome: BatchOutcome = process_files(file_paths.clone(), &options, |_| {});
+        assert_eq!(outcome.files, 8);
+        assert_eq!(outcome.failed_files, 0);
+        for (file_path, gcode) in file_paths.iter().zip(&gcodes) {
+            let (expected, _) = convert(gcode, &Options::default());
+            assert_eq!(
+                std::fs::read_to_string(file_path).unwrap(),
+                expected.unwrap()
+            );
+        }
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn only_the_normal_mode_time_is_used() {
+        let silent_mode: &str = "; estimated printing time (silent mode) = 1h 5m 3s";
+        for gcode in [
+            FIXTURE.replace(
+                "; estimated printing time (normal mode) = 1h 2m 3s",
+                &format!("; estimated printing time (normal mode) = 1h 2m 3s\n{silent_mode}"),
+            ),
+            FIXTURE.replace(
+                "; estimated printing time (normal mode) = 1h 2m 3s",
+                &format!("{silent_mode}\n; estimated printing time (normal mode) = 1h 2m 3s"),
+            ),
+        ] {
+            let (converted, _) = convert(&gcode, &Options::default());
+            let converted: String = converted.unwrap();
+            let times: Vec<&str> = converted
+                .lines()
+                .filter(|line| line.starts_with(";TIME:"))
+                .collect();
+            assert_eq!(times, [";TIME:3723"]);
+        }
+
+        // Without a normal mode estimate, the first one is used
+        let (converted, _) = convert(
+            &FIXTURE.replace("(normal mode) = 1h 2m 3s", "(silent mode) = 1h 5m 3s"),
+            &Options::default(),
+        );
+        assert_eq!(header_value(&converted.unwrap(), "TIME"), Some("3903"));
+    }
+
+    #[test]
+    fn inspecting_several_files_gives_a_json_line_per_file() {
+        let directory: PathBuf = temporary_directory("inspect");
+        let file_paths: Vec<String> = [
+            ("a.gcode", FIXTURE.to_string()),
+            (
+                "b.gcode",
+                FIXTURE.replace(
+                    "; estimated printing time (normal mode) = 1h 2m 3s",
+                    "; estimated printing time (normal mode) = 1h 2m 3s\n\
+                    ; estimated printing time (silent mode) = 1h 5m 3s",
+                ),
+            ),
+        ]
+        .iter()
+        .map(|(file_name, gcode)| {
+            let file_path: PathBuf = directory.join(file_name);
+            std::fs::write(&file_path, gcode).unwrap();
+            file_path.display().to_string()
+        })
+        .collect();
+        let options: Options = Options {
+            inspect: true,
+            ..Options::default()
+        };
+
+        let mut reported: Vec<String> = Vec::new();
+        process_files(file_paths.clone(), &options, |log| {
+            reported.extend(log.stdout.iter().cloned())
+        });
+        assert_eq!(reported.len(), 2);
+        for (line, file_path) in reported.iter().zip(&file_paths) {
+            let JsonValue::Object(members) = JsonValue::parse(line).unwrap() else {
+                panic!("{line} is not a JSON object");
+            };
+            assert_eq!(
+                members[0],
+                ("path".to_string(), JsonValue::String(file_path.clone()))
+            );
+            let times: Vec<&JsonValue> = members
+                .iter()
+                .filter(|(key, _)| key == "time_seconds")
+                .map(|(_, value)| value)
+                .collect();
+            assert_eq!(times, [&JsonValue::Number(3723.0)]);
+        }
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn header_only_file_is_accepted_with_allow_empty_body() {
+        let gcode: &str = "\
+; filament used [mm] = 3.00
+; estimated printing time (normal mode) = 1h 2m 3s
+";
+
+        let (converted, log) = convert(gcode, &Options::default());
+        assert!(converted.is_ok());
+        assert!(log.diagnostics.contains(&Diagnostic::NoGcodeBody));
+
+        let options: Options = Options {
+            allow_empty_body: true,
+            ..Options::default()
+        };
+        let (converted, log) = convert(gcode, &options);
+        assert_eq!(header_value(&converted.unwrap(), "TIME"), Some("3723"));
+        assert!(log.diagnostics.is_empty());
+    }
+
+    #[test]
+    fn total_toolchanges_are_reported_for_mmu_prints() {
+        let mmu: String = FIXTURE.replace(
+            "; filament used [mm] = 3.00",
+            "; filament used [mm] = 1.50, 1.50\n; total toolchanges = 12",
+        );
+        let lines: Vec<String> = mmu.lines().map(String::from).collect();
+        let report: PrintReport =
+            PrintReport::from_lines(&classify_lines(&lines), &Options::default());
+        assert_eq!(report.total_toolchanges, 12);
+        assert!(report
+            .to_json_members()
+            .contains(&"\"total_toolchanges\":12".to_string()));
+
+        // Single material prints don't record any
+        let lines: Vec<String> = FIXTURE.lines().map(String::from).collect();
+        let report: PrintReport =
+            PrintReport::from_lines(&classify_lines(&lines), &Options::default());
+        assert_eq!(report.total_toolchanges, 0);
+    }
+
+    #[test]
+    fn file_cut_short_is_reported_as_truncated() {
+        let (_, log) = convert(FIXTURE, &Options::default());
+        assert!(!log.diagnostics.contains(&Diagnostic::LooksTruncated));
+
+        // Cut off part way through the config block
+        let truncated: &str = FIXTURE.trim_end_matches("; prusaslicer_config = end\n");
+        let (_, log) = convert(truncated, &Options::default());
+        assert!(log.diagnostics.contains(&Diagnostic::LooksTruncated));
+
+        // Cut off before the estimates
+        let truncated: &str = &FIXTURE[..FIXTURE.find("; filament used").unwrap()];
+        let (_, log) = convert(truncated, &Options::default());
+        assert!(log.diagnostics.contains(&Diagnostic::LooksTruncated));
+    }
+
+    #[test]
+    fn prusaslicer_gcode_flavours_are_translated() {
+        assert_eq!(translate_gcode_flavor("marlin2"), Some("Marlin"));
+        assert_eq!(translate_gcode_flavor(" marlin "), Some("Marlin"));
+        assert_eq!(translate_gcode_flavor("klipper"), None);
+        assert_eq!(translate_gcode_flavor("reprapfirmware"), None);
+    }
+
+    #[test]
+    fn header_from_another_tool_is_not_duplicated() {
+        let gcode: String =
+            format!(";FLAVOR:Marlin\n;TIME:1000\n;Filament used: 9.00000m\n;BEDTEMP:70\n{FIXTURE}");
+
+        assert_eq!(ankermake_header_key(";BEDTEMP:70"), Some("BEDTEMP"));
+        assert_eq!(ankermake_header_key(";PRINTER:M5"), None);
+
+        let (converted, _) = convert(&gcode, &Options::default());
+        let converted: String = converted.unwrap();
+        let count = |prefix: &str| {
+            converted
+                .lines()
+                .filter(|line| line.starts_with(prefix))
+                .count()
+        };
+        assert_eq!(count(";FLAVOR:"), 1);
+        assert_eq!(count(";TIME:"), 1);
+        assert_eq!(count(";Filament used:"), 1);
+        assert_eq!(header_value(&converted, "TIME"), Some("3723"));
+        assert_eq!(header_value(&converted, "Filament used"), Some(" 0.00300m"));
+        // Fields that weren't generated from the file are left as they were
+        assert_eq!(header_value(&converted, "BEDTEMP"), Some("70"));
+    }
+
+    #[test]
+    fn perimeter_ordering_is_reported_when_the_config_records_it() {
+        let gcode: String = FIXTURE.replace(
+            "; gcode_flavor = marlin2",
+            "; gcode_flavor = marlin2\n; external_perimeters_first = 1\n; infill_first = 0\n; infill_first_layer = 1",
+        );
+        let lines: Vec<String> = gcode.lines().map(String::from).collect();
+        let report: PrintReport =
+            PrintReport::from_lines(&classify_lines(&lines), &Options::default());
+        assert_eq!(report.external_perimeters_first, Some(true));
+        assert_eq!(report.infill_first, Some(false));
+        let members: Vec<String> = report.to_json_members();
+        assert!(members.contains(&"\"external_perimeters_first\":true".to_string()));
+        assert!(members.contains(&"\"infill_first\":false".to_string()));
+
+        let lines: Vec<String> = FIXTURE.lines().map(String::from).collect();
+        let members: Vec<String> =
+            PrintReport::from_lines(&classify_lines(&lines), &Options::default()).to_json_members();
+        assert!(!members.iter().any(|member| member.contains("first")));
+    }
+
+    #[test]
+    fn color_changes_are_reported_with_their_layers() {
+        let lines: Vec<String> = COLOR_CHANGE_FIXTURE.lines().map(String::from).collect();
+        let report: PrintReport =
+            PrintReport::from_lines(&classify_lines(&lines), &Options::default());
+        assert_eq!(
+            report.color_changes,
+            [
+                ColorChange {
+                    layer: 2,
+                    z: Some(0.4)
+                },
+                ColorChange {
+                    layer: 3,
+                    z: Some(0.6)
+                },
+            ]
+        );
+        let members: Vec<String> = report.to_json_members();
+        assert!(members.contains(&"\"color_change_count\":2".to_string()));
+        assert!(members.contains(
+            &"\"color_changes\":[{\"layer\":2,\"z\":0.4},{\"layer\":3,\"z\":0.6}]".to_string()
+        ));
+    }
+
+    #[test]
+    fn info_block_delimiters_enclose_the_header_and_are_not_repeated() {
+        let options: Options = Options {
+            info_block: true,
+            ..Options::default()
+        };
+        let (converted, _) = convert(FIXTURE, &options);
+        let converted: String = converted.unwrap();
+        let mut lines = converted.lines();
+        assert_eq!(lines.next(), Some(ANKERMAKE_INFO_BLOCK_BEGIN));
+        assert_eq!(lines.next(), Some(";FLAVOR:Marlin"));
+        let block: Vec<&str> = lines
+            .by_ref()
+            .take_while(|line| *line != ANKERMAKE_INFO_BLOCK_END)
+            .collect();
+        assert!(block.contains(&";TIME:3723"));
+        assert_eq!(
+            lines.next(),
+            Some("; generated by PrusaSlicer 2.6.0+linux-x64-GTK3 on 2023-06-27 at 10:00:00 UTC")
+        );
+
+        // Converting again without the block leaves no empty delimiters behind
+        let (flat, _) = convert(&converted, &Options::default());
+        let (expected, _) = convert(FIXTURE, &Options::default());
+        assert_eq!(flat.unwrap(), expected.unwrap());
+
+        // A block that still holds fields which aren't generated again is kept
+        let gcode: String = format!("{ANKERMAKE_INFO_BLOCK_BEGIN}\n;TIME:10\n;BEDTEMP:70\n{ANKERMAKE_INFO_BLOCK_END}\n{FIXTURE}");
+        let (converted, _) = convert(&gcode, &Options::default());
+        assert!(converted.unwrap().contains(&format!(
+            "\n{ANKERMAKE_INFO_BLOCK_BEGIN}\n;BEDTEMP:70\n{ANKERMAKE_INFO_BLOCK_END}\n"
+        )));
+    }
+
+    #[test]
+    fn single_line_without_a_newline() {
+        let (converted, _) = convert("G28", &Options::default());
+        assert_eq!(
+            converted.unwrap(),
+            format!(
+                ";FLAVOR:Marlin\n;PostProcessor:{POST_PROCESSOR_NAME} {}\nG28",
+                version()
+            )
+        );
+
+        let (converted, _) = convert(
+            "; estimated printing time (normal mode) = 1h 2m 3s",
+            &Options::default(),
+        );
+        let converted: String = converted.unwrap();
+        assert_eq!(header_value(&converted, "TIME"), Some("3723"));
+        assert!(converted.ends_with("\n; estimated printing time (normal mode) = 1h 2m 3s"));
+    }
+
+    #[test]
+    fn binary_gcode_is_detected_by_its_signature() {
+        let binary_gcode: &[u8] = b"GCDE\x01\x00\x00\x00\x01\x00";
+        assert!(is_binary_gcode(Path::new("print.gcode"), &mut &binary_gcode[..]).unwrap());
+        assert!(!is_binary_gcode(Path::new("print.gcode"), &mut FIXTURE.as_bytes()).unwrap());
+
+        let directory: PathBuf = temporary_directory("binary-gcode");
+        let file_path: PathBuf = directory.join("print.gcode");
+        std::fs::write(&file_path, binary_gcode).unwrap();
+
+        let file_path_string: String = file_path.display().to_string();
+        let log: FileLog = process_file(file_path_string.clone(), None, &Options::default());
+        assert_eq!(log.diagnostics, [Diagnostic::BinaryGcode(file_path_string)]);
+        assert_eq!(std::fs::read(&file_path).unwrap(), binary_gcode);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn filament_is_totalled_per_color() {
+        let lines: Vec<String> = COLOR_CHANGE_FIXTURE.lines().map(String::from).collect();
+        let report: PrintReport =
+            PrintReport::from_lines(&classify_lines(&lines), &Options::default());
+        assert_eq!(report.filament_per_color_mm, [2.0, 3.0, 1.5]);
+        assert!(report
+            .to_json_members()
+            .contains(&"\"filament_per_color_mm\":[2.00,3.00,1.50]".to_string()));
+    }
+
+    #[test]
+    fn unchanged_file_is_not_rewritten_when_only_writing_changes() {
+        let directory: PathBuf = temporary_directory("if-changed");
+        let file_path: PathBuf = directory.join("print.gcode");
+        let (converted, _) = convert(FIXTURE, &Options::default());
+        let converted: String = converted.unwrap();
+        std::fs::write(&file_path, &converted).unwrap();
+        let modified: std::time::SystemTime =
+            std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+        File::options()
+            .write(true)
+            .open(&file_path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        let options: Options = Options {
+            overwrite_policy: OverwritePolicy::IfChanged,
+            ..Options::default()
+        };
+
+        let log: FileLog = process_file(file_path.display().to_string(), None, &options);
+        assert!(!log.has_errors());
+        assert_eq!(
+            std::fs::metadata(&file_path).unwrap().modified().unwrap(),
+            modified
+        );
+        assert_eq!(std::fs::read_to_string(&file_path).unwrap(), converted);
+
+        // A file that does change is still written
+        std::fs::write(&file_path, FIXTURE).unwrap();
+        process_file(file_path.display().to_string(), None, &options);
+        assert_eq!(std::fs::read_to_string(&file_path).unwrap(), converted);
+        assert_eq!(file_names(&directory), ["print.gcode"]);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn legacy_filament_used_is_recognised() {
+        let gcode: String = FIXTURE
+            .replace(
+                "; filament used [mm] = 3.00",
+                "; filament used = 1234.5mm (3.0cm3)",
+            )
+            .replace("(normal mode) = ", "= ");
+
+        let (converted, _) = convert(&gcode, &Options::default());
+        let converted: String = converted.unwrap();
+        assert_eq!(header_value(&converted, "Filament used"), Some(" 1.23450m"));
+        assert_eq!(header_value(&converted, "TIME"), Some("3723"));
+
+        // The modern attribute wins when both are there
+        let gcode: String = FIXTURE.replace(
+            "; filament used [mm] = 3.00",
+            "; filament used = 1234.5mm (3.0cm3)\n; filament used [mm] = 3.00",
+        );
+        let (converted, _) = convert(&gcode, &Options::default());
+        assert_eq!(
+            header_value(&converted.unwrap(), "Filament used"),
+            Some(" 0.00300m")
+        );
+    }
+
+    #[test]
+    fn start_gcode_goes_between_the_header_and_the_body() {
+        let options: Options = Options {
+            start_gcode: vec!["G29".to_string(), "G1 Z5".to_string()],
+            ..Options::default()
+        };
+
+        let (converted, _) = convert(FIXTURE, &options);
+        let converted: String = converted.unwrap();
+        let lines: Vec<&str> = converted.lines().collect();
+        let start: usize = lines
+            .iter()
+            .position(|line| *line == START_GCODE_BEGIN)
+            .unwrap();
+        assert!(lines[..start]
+            .iter()
+            .all(|line| ankermake_header_key(line).is_some()));
+        assert_eq!(
+            lines[start..start + 5],
+            [
+                START_GCODE_BEGIN,
+                "G29",
+                "G1 Z5",
+                START_GCODE_END,
+                "; generated by PrusaSlicer 2.6.0+linux-x64-GTK3 on 2023-06-27 at 10:00:00 UTC"
+            ]
+        );
+
+        // Converting again replaces the start gcode rather than repeating it
+        let (converted_again, _) = convert(&converted, &options);
+        assert_eq!(converted_again.unwrap(), converted);
+    }
+
+    #[test]
+    fn metadata_keys_match_regardless_of_case() {
+        let gcode: String = FIXTURE
+            .replace("; filament used [mm]", "; Filament Used [MM]")
+            .replace(
+                "; estimated printing time (normal mode) = 1h 2m 3s",
+                "; Estimated Printing Time (Silent Mode) = 1h 5m 3s\n; ESTIMATED PRINTING TIME (NORMAL MODE) = 1h 2m 3s",
+            );
+
+        let (converted, _) = convert(&gcode, &Options::default());
+        let converted: String = converted.unwrap();
+        assert_eq!(header_value(&converted, "Filament used"), Some(" 0.00300m"));
+        assert_eq!(header_value(&converted, "TIME"), Some("3723"));
+
+        // A longer key sharing the prefix doesn't match
+        assert!(has_attribute_key("Infill_First = 1", PRUSA_INFILL_FIRST));
+        assert!(!has_attribute_key(
+            "infill_first_layer = 1",
+            PRUSA_INFILL_FIRST
+        ));
+    }
+
+    #[test]
+    fn swapped_diameters_are_warned_about() {
+        let with_diameters = |nozzle_diameter: &str, filament_diameter: &str| -> FileLog {
+            let gcode: String = FIXTURE.replace(
+                "; gcode_flavor = marlin2",
+                &format!(
+                    "; gcode_flavor = marlin2\n; nozzle_diameter = {nozzle_diameter}\n; filament_diameter = {filament_diameter}"
+                ),
+            );
+            let (converted, log) = convert(&gcode, &Options::default());
+            assert!(converted.is_ok());
+            log
+        };
+
+        assert!(with_diameters("1.75,1.75", "0.4,0.4").diagnostics.contains(
+            &Diagnostic::SwappedDiameters {
+                nozzle_diameter: 1.75,
+                filament_diameter: 0.4
+            }
+        ));
+        assert!(with_diameters("0.4", "1.75").diagnostics.is_empty());
+    }
+
+    /// Adds the name of the slicer that generated the file to the header
+    #[derive(Debug)]
+    struct SlicerExtractor;
+
+    impl FieldExtractor for SlicerExtractor {
+        fn try_extract(&self, line: &LineKind) -> Option<InterestingFields> {
+            let LineKind::Comment(comment) = line else {
+                return None;
+            };
+            let slicer: &str = comment
+                .strip_prefix("; generated by ")?
+                .split_whitespace()
+                .next()?;
+            Some(InterestingFields::Custom {
+                key: "SLICER",
+                value: slicer.to_string(),
+            })
+        }
+    }
+
+    #[test]
+    fn custom_extractors_add_their_own_header_fields() {
+        let options: Options = Options {
+            extractors: vec![Arc::new(SlicerExtractor)],
+            ..Options::default()
+        };
+
+        let (converted, _) = convert(FIXTURE, &options);
+        let converted: String = converted.unwrap();
+        assert_eq!(header_value(&converted, "SLICER"), Some("PrusaSlicer"));
+        let header: Vec<&str> = converted
+            .lines()
+            .take_while(|line| !line.starts_with("; generated by"))
+            .collect();
+        assert_eq!(header.last(), Some(&";SLICER:PrusaSlicer"));
+
+        // Converting again replaces the field rather than repeating it
+        let (converted_again, _) = convert(&converted, &options);
+        assert_eq!(converted_again.unwrap(), converted);
+    }
+
+    #[test]
+    fn time_offset_is_added_and_floored_at_zero() {
+        let with_offset = |time_offset: i64| -> String {
+            let options: Options = Options {
+                time_offset,
+                ..Options::default()
+            };
+            InterestingFields::Time(3723).to_header_line(&options)
+        };
+
+        assert_eq!(with_offset(300), ";TIME:4023");
+        assert_eq!(with_offset(-723), ";TIME:3000");
+        assert_eq!(with_offset(-10000), ";TIME:0");
+    }
+
+    #[test]
+    fn objects_info_is_parsed_into_objects() {
+        let objects_info: &str = r#"{"objects":[{"name":"cube.stl_id_0_copy_0","polygon":[[10.0,10.0],[30.0,10.0],[30.0,40.0],[10.0,40.0]]},{"name":"pin \"a\"","polygon":[[50,50],[55,50],[55,55]]}]}"#;
+        assert_eq!(
+            ObjectInfo::from_objects_info(objects_info),
+            Some(vec![
+                ObjectInfo {
+                    name: "cube.stl_id_0_copy_0".to_string(),
+                    polygon: vec![[10.0, 10.0], [30.0, 10.0], [30.0, 40.0], [10.0, 40.0]],
+                },
+                ObjectInfo {
+                    name: "pin \"a\"".to_string(),
+                    polygon: vec![[50.0, 50.0], [55.0, 50.0], [55.0, 55.0]],
+                },
+            ])
+        );
+
+        let gcode: String = format!("; objects_info = {objects_info}\n{FIXTURE}");
+        let lines: Vec<String> = gcode.lines().map(String::from).collect();
+        let report: PrintReport =
+            PrintReport::from_lines(&classify_lines(&lines), &Options::default());
+        assert!(report.to_json_members().contains(
+            &r#""objects":[{"name":"cube.stl_id_0_copy_0","polygon_points":4},{"name":"pin \"a\"","polygon_points":3}]"#
+                .to_string()
+        ));
+
+        // Malformed blocks are left out rather than failing the file
+        assert_eq!(
+            ObjectInfo::from_objects_info(r#"{"objects":[{"name":"cube"}]}"#),
+            None
+        );
+        assert_eq!(ObjectInfo::from_objects_info(r#"{"objects":["#), None);
+    }
+
+    #[test]
+    fn missing_time_is_reported_as_a_diagnostic() {
+        let gcode: String =
+            FIXTURE.replace("; estimated printing time (normal mode) = 1h 2m 3s\n", "");
+
+        let (converted, log) = convert(&gcode, &Options::default());
+        assert_eq!(header_value(&converted.unwrap(), "TIME"), None);
+        assert!(log
+            .diagnostics
+            .contains(&Diagnostic::MissingField(ANKERMAKE_PRINTING_TIME)));
+        assert!(!log
+            .diagnostics
+            .contains(&Diagnostic::MissingField(ANKERMAKE_FILAMENT_USED_M)));
+        assert!(!log.has_errors());
+    }
+
+    #[test]
+    fn header_sidecar_holds_the_header_lines_and_leaves_the_gcode_alone() {
+        let directory: PathBuf = temporary_directory("header-sidecar");
+        let file_path: PathBuf = directory.join("print.gcode");
+        let gcode: String = FIXTURE.replace('\n', "\r\n");
+        std::fs::write(&file_path, &gcode).unwrap();
+        let options: Options = Options {
+            header_sidecar: true,
+            ..Options::default()
+        };
+
+        let log: FileLog = process_file(file_path.display().to_string(), None, &options);
+        assert!(!log.has_errors());
+        assert_eq!(std::fs::read_to_string(&file_path).unwrap(), gcode);
+        assert_eq!(
+            std::fs::read_to_string(directory.join("print.gcode.header")).unwrap(),
+            format!(
+                ";FLAVOR:Marlin\r\n;PostProcessor:{POST_PROCESSOR_NAME} {}\r\n;TIME:3723\r\n;Filament used: 0.00300m\r\n\
+                ;LAYER_COUNT:2\r\n;MINX:10.00\r\n;MAXX:20.00\r\n;MINY:10.00\r\n;MAXY:20.00\r\n;MINZ:0.20\r\n;MAXZ:0.40\r\n",
+                version()
+            )
+        );
+        assert_eq!(
+            file_names(&directory),
+            ["print.gcode", "print.gcode.header"]
+        );
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn filament_used_is_never_written_in_scientific_notation() {
+        for (length_umx10, meters, millimeters) in [
+            (1, "0.00001m", "0.01mm"),
+            (0, "0.00000m", "0.00mm"),
+            (123456789012345, "1234567890.12345m", "1234567890123.45mm"),
+        ] {
+            let field: InterestingFields = InterestingFields::FilamentUsed(length_umx10);
+            let header_line: String = field.to_header_line(&Options::default());
+            assert_eq!(header_line, format!(";Filament used: {meters}"));
+            let options: Options = Options {
+                filament_unit: FilamentUnit::Millimeters,
+                ..Options::default()
+            };
+            assert_eq!(
+                field.to_header_line(&options),
+                format!(";Filament used: {millimeters}")
+            );
+            let (_, value) = header_line.split_once(": ").unwrap();
+            assert!(!value.contains(['e', 'E']));
+        }
+    }
+
+    #[test]
+    fn future_slicer_version_gets_an_advisory_but_is_still_converted() {
+        let gcode: String = FIXTURE.replace("PrusaSlicer 2.6.0+linux", "PrusaSlicer 3.1.0+linux");
+
+        let (converted, log) = convert(&gcode, &Options::default());
+        assert_eq!(header_value(&converted.unwrap(), "TIME"), Some("3723"));
+        assert_eq!(
+            log.diagnostics,
+            [Diagnostic::UntestedSlicerVersion(
+                "3.1.0+linux-x64-GTK3".to_string()
+            )]
+        );
+        assert!(!log.has_errors());
+
+        let (_, log) = convert(FIXTURE, &Options::default());
+        assert!(log.diagnostics.is_empty());
+        assert!(is_untested_prusaslicer_version("2.8.0"));
+        assert!(!is_untested_prusaslicer_version("2.7.1"));
+    }
+
+    #[test]
+    fn header_from_a_future_post_processor_version_is_replaced() {
+        let gcode: String = format!(
+            ";FLAVOR:Marlin\n;PostProcessor:{POST_PROCESSOR_NAME} 99.0.0\n;TIME:10\n{FIXTURE}"
+        );
+
+        let (converted, _) = convert(&gcode, &Options::default());
+        let (expected, _) = convert(FIXTURE, &Options::default());
+        assert_eq!(converted.unwrap(), expected.unwrap());
+
+        // It's still recognised as converted when searching a directory
+        let directory: PathBuf = temporary_directory("future-post-processor");
+        let file_path: PathBuf = directory.join("print.gcode");
+        std::fs::write(&file_path, &gcode).unwrap();
+        assert!(looks_converted(&file_path).unwrap());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn time_formulas_are_evaluated_and_invalid_ones_rejected() {
+        let apply = |formula: &str, seconds: u64| -> u64 {
+            formula.parse::<TimeFormula>().unwrap().apply(seconds)
+        };
+        assert_eq!(apply("t * 1.1 + 300", 1000), 1400);
+        assert_eq!(apply("(t - 100) / 2", 1000), 450);
+        assert_eq!(apply("-t", 1000), 0);
+        assert_eq!(apply("t / 0", 1000), 0);
+
+        for formula in ["", "t +", "t * (2", "t ^ 2", "x", "t; rm -rf /", "2 t"] {
+            assert!(formula.parse::<TimeFormula>().is_err(), "{formula}");
+        }
+
+        let options: Options = Options {
+            time_formula: Some("t * 2".parse().unwrap()),
+            time_offset: 10,
+            ..Options::default()
+        };
+        assert_eq!(
+            InterestingFields::Time(3723).to_header_line(&options),
+            ";TIME:7456"
+        );
+    }
+
+    #[test]
+    fn report_rows_list_temperatures_and_features() {
+        let gcode: String = FIXTURE
+            .replace(
+                "; gcode_flavor = marlin2",
+                "; bed_temperature = 60\n; filament_type = PLA\n; gcode_flavor = marlin2",
+            )
+            .replace("G1 Z0.4\n", "G1 Z0.4\n;COLOR_CHANGE,T0,#FF8000\nM600\n");
+        let lines: Vec<String> = gcode.lines().map(String::from).collect();
+        let line_kinds: Vec<LineKind> = classify_lines(&lines);
+        let (interesting_fields, _) = extract_interesting_fields(&line_kinds, &Options::default());
+
+        let summary: FileSummary = FileSummary::from_lines(
+            "print.gcode",
+            &line_kinds,
+            interesting_fields,
+            &Options::default(),
+        );
+        assert_eq!(
+            summary.to_report_row(),
+            "  1h 02m 03s         3.00     0.00300       2     60     210*       2            0  \
+            color_changes                 print.gcode"
+        );
+        assert_eq!(
+            format_report_row(REPORT_COLUMNS),
+            "        time  filament_mm  filament_m  layers  bed_c hotend_c  colors  toolchanges  \
+            features                      path"
+        );
+    }
+
+    #[test]
+    fn feed_rates_carry_over_to_later_moves() {
+        let lines: Vec<String> = "G1 X1 F1200\nG1 X2 F3000\nG1 X3\nG28 F9000\nG0 X4 F600"
+            .lines()
+            .map(String::from)
+            .collect();
+        let report: PrintReport =
+            PrintReport::from_lines(&classify_lines(&lines), &Options::default());
+        assert_eq!(
+            report.feed_rate,
+            Some(FeedRateStats {
+                min: 600.0,
+                max: 3000.0,
+                average: 1950.0,
+            })
+        );
+        assert!(report.to_json_members().contains(
+            &"\"feed_rate_mm_per_min\":{\"min\":600,\"max\":3000,\"average\":1950.00}".to_string()
+        ));
+    }
+
+    #[test]
+    fn stripped_config_block_still_feeds_the_header() {
+        let gcode: String = FIXTURE
+            .replace(
+                "; filament used [mm] = 3.00\n; estimated printing time (normal mode) = 1h 2m 3s\n",
+                "",
+            )
+            .replace(
+                "; gcode_flavor = marlin2",
+                "; filament used [mm] = 3.00\n\
+                ; estimated printing time (normal mode) = 1h 2m 3s\n\
+                ; gcode_flavor = marlin2",
+            );
+        let options: Options = Options {
+            strip_prusa_config: true,
+            ..Options::default()
+        };
+
+        let (converted, _) = convert(&gcode, &options);
+        let converted: String = converted.unwrap();
+        assert_eq!(
+            header_value(&converted, ANKERMAKE_PRINTING_TIME),
+            Some("3723")
+        );
+        assert_eq!(
+            header_value(&converted, ANKERMAKE_FILAMENT_USED_M),
+            Some(" 0.00300m")
+        );
+        assert!(!converted.contains("prusaslicer_config"));
+        assert!(!converted.contains("estimated printing time"));
+        assert!(!converted.contains("gcode_flavor"));
+        assert!(converted.contains("G1 X20 Y20 E1.5\n"));
+    }
+
+    #[test]
+    fn time_too_long_to_count_is_an_error() {
+        assert_eq!(
+            extract_time_data_as_seconds(
+                "estimated printing time (normal mode) = 999999999999999999d"
+            ),
+            Err(ParsingError::StringParsingError(
+                "u64",
+                "999999999999999999d".to_string()
+            ))
+        );
+        // Each part fits on its own, but not once they're added up
+        assert_eq!(
+            extract_time_data_as_seconds(
+                "estimated printing time (normal mode) = 213503982334601d 213503982334601d"
+            ),
+            Err(ParsingError::StringParsingError(
+                "u64",
+                "213503982334601d 213503982334601d".to_string()
+            ))
+        );
+        assert_eq!(
+            extract_time_data_as_seconds(
+                "estimated printing time (normal mode) = 213503982334601d"
+            ),
+            Ok(213503982334601 * 24 * 60 * 60)
+        );
+    }
+
+    #[test]
+    fn filament_weight_is_estimated_from_the_length_used() {
+        let gcode: String = FIXTURE
+            .replace("filament used [mm] = 3.00", "filament used [mm] = 1000.00")
+            .replace(
+                "; gcode_flavor = marlin2",
+                "; filament_density = 0\n; filament_type = PLA\n; gcode_flavor = marlin2",
+            );
+        let options: Options = Options {
+            filament_weight: true,
+            ..Options::default()
+        };
+
+        let (converted, _) = convert(&gcode, &options);
+        let converted: String = converted.unwrap();
+        assert_eq!(
+            header_value(&converted, ANKERMAKE_FILAMENT_USED_G),
+            Some("2.98")
+        );
+        assert!(converted.contains(&format!(
+            "{ANKERMAKE_ESTIMATED_WEIGHT_NOTE}\n;{ANKERMAKE_FILAMENT_USED_G}:2.98\n"
+        )));
+
+        // Converting again replaces the estimate and its note rather than repeating them
+        let (reconverted, _) = convert(&converted, &options);
+        assert_eq!(reconverted.unwrap(), converted);
+
+        // The density and diameter in the config are used over the defaults
+        let (converted, _) = convert(
+            &gcode.replace(
+                "; filament_density = 0",
+                "; filament_density = 1.27\n; filament_diameter = 2.85",
+            ),
+            &options,
+        );
+        assert_eq!(
+            header_value(&converted.unwrap(), ANKERMAKE_FILAMENT_USED_G),
+            Some("8.10")
+        );
+
+        // Prusaslicer's own weight is used as it is, with no note
+        let (converted, _) = convert(
+            &gcode.replace(
+                "; filament used [mm] = 1000.00",
+                "; filament used [mm] = 1000.00\n; filament used [g] = 3.10",
+            ),
+            &options,
+        );
+        let converted: String = converted.unwrap();
+        assert_eq!(
+            header_value(&converted, ANKERMAKE_FILAMENT_USED_G),
+            Some("3.10")
+        );
+        assert!(!converted.contains(ANKERMAKE_ESTIMATED_WEIGHT_NOTE));
+
+        let (converted, _) = convert(&gcode, &Options::default());
+        assert_eq!(
+            header_value(&converted.unwrap(), ANKERMAKE_FILAMENT_USED_G),
+            None
+        );
+    }
+
+    #[test]
+    fn filament_weight_is_worked_out_from_the_volume() {
+        assert_eq!(estimate_filament_weight_as_cg(100000, 1.75, 1.24), 298);
+        assert_eq!(estimate_filament_weight_as_cg(0, 1.75, 1.24), 0);
+        assert_eq!(default_filament_density("petg"), Some(1.27));
+        assert_eq!(default_filament_density("WOOD"), None);
+    }
+
+    #[test]
+    fn lenient_skips_only_the_lines_it_cant_parse() {
+        let gcode: String = FIXTURE.replace(
+            "; estimated printing time (normal mode)",
+            "; estimated printing time (silent mode) = 2h 5x\n; estimated printing time (normal mode)",
+        );
+        let skipped_error: ParsingError =
+            ParsingError::StringParsingError("duration", "5x".to_string());
+
+        let (converted, _) = convert(&gcode, &Options::default());
+        assert_eq!(
+            converted,
+            Err(ParsingError::InvalidLines(vec![(
+                13,
+                skipped_error.clone()
+            )]))
+        );
+
+        let options: Options = Options {
+            lenient: true,
+            ..Options::default()
+        };
+        let (converted, log) = convert(&gcode, &options);
+        assert_eq!(
+            header_value(&converted.unwrap(), ANKERMAKE_PRINTING_TIME),
+            Some("3723")
+        );
+        assert_eq!(
+            log.diagnostics,
+            [Diagnostic::SkippedLine {
+                line: 13,
+                error: skipped_error
+            }]
+        );
+    }
+
+    #[test]
+    fn config_block_is_found_between_its_markers() {
+        let find = |gcode: &str| -> Option<ConfigBlock> {
+            let lines: Vec<String> = gcode.lines().map(String::from).collect();
+            ConfigBlock::find(&classify_lines(&lines))
+        };
+
+        assert_eq!(
+            find("; prusaslicer_config = begin\n; gcode_flavor = marlin2\n; prusaslicer_config = end"),
+            Some(ConfigBlock {
+                begin: 0,
+                end: Some(2)
+            })
+        );
+        // An end marker before the block begins doesn't close it
+        let unclosed: ConfigBlock = find(
+            "; prusaslicer_config = end\nG28\n; prusaslicer_config = begin\n; gcode_flavor = marlin2",
+        )
+        .unwrap();
+        assert_eq!(
+            unclosed,
+            ConfigBlock {
+                begin: 2,
+                end: None
+            }
+        );
+        assert_eq!(unclosed.range(4), 2..4);
+        assert!(!unclosed.is_closed());
+        // Only the exact key is a marker
+        assert_eq!(find("; prusaslicer_config_version = begin\nG28"), None);
+        assert_eq!(find(FIXTURE).map(|block| block.range(16)), Some(13..16));
+    }
+
+    #[test]
+    fn filament_used_too_long_to_add_up_is_an_error() {
+        assert_eq!(
+            extract_filament_used_as_um_x10("filament used [mm] = 184467440737095516.15, 1.00"),
+            Err(ParsingError::StringParsingError(
+                "u64",
+                "184467440737095516.15, 1.00".to_string()
+            ))
+        );
+        assert_eq!(
+            extract_filament_used_as_um_x10("filament used [mm] = 184467440737095516.15, 0.00"),
+            Ok(u64::MAX)
+        );
+    }
+
+    #[test]
+    fn trace_file_lists_how_each_line_was_classified() {
+        let directory: PathBuf = temporary_directory("trace-gcode");
+        let file_path: PathBuf = directory.join("print.gcode");
+        std::fs::write(&file_path, FIXTURE.replace("M83\n", "M83\n  \n")).unwrap();
+        let options: Options = Options {
+            trace_gcode: true,
+            ..Options::default()
+        };
+
+        let log: FileLog = process_file(file_path.display().to_string(), None, &options);
+        assert!(!log.has_errors());
+        let trace: String = std::fs::read_to_string(directory.join("print.gcode.trace")).unwrap();
+        let trace: Vec<&str> = trace.lines().collect();
+        assert_eq!(trace.len(), FIXTURE.lines().count() + 1);
+        assert_eq!(
+            trace[..4],
+            [
+                "1\tComment(\"; generated by PrusaSlicer 2.6.0+linux-x64-GTK3 on 2023-06-27 at 10:00:00 UTC\")",
+                "2\tInstruction(\"M83\")",
+                "3\tBlank",
+                "4\tComment(\";LAYER_CHANGE\")",
+            ]
+        );
+        assert_eq!(trace[14], "15\tMetadata(\"prusaslicer_config = begin\")");
+        assert_eq!(file_names(&directory), ["print.gcode", "print.gcode.trace"]);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn short_and_unspaced_comments_are_classified() {
+        assert_eq!(
+            LineKind::classify(";key=val"),
+            LineKind::Metadata("key=val")
+        );
+        assert_eq!(LineKind::classify(";a="), LineKind::Metadata("a="));
+        assert_eq!(LineKind::classify(";;;"), LineKind::Comment(";;;"));
+        assert_eq!(LineKind::classify("G28"), LineKind::Instruction("G28"));
+
+        let (converted, _) = convert(
+            "G1 X10 Y10 E1\n;filament used [mm]=1.00\n;estimated printing time (normal mode)=1m\n",
+            &Options::default(),
+        );
+        let converted: String = converted.unwrap();
+        assert_eq!(
+            header_value(&converted, ANKERMAKE_PRINTING_TIME),
+            Some("60")
+        );
+        assert_eq!(
+            header_value(&converted, ANKERMAKE_FILAMENT_USED_M),
+            Some(" 0.00100m")
+        );
+    }
+
+    #[test]
+    fn multibyte_characters_are_never_split() {
+        // The second byte of each of these is inside a multi-byte character
+        assert_eq!(LineKind::classify(";é=1"), LineKind::Metadata("é=1"));
+        assert_eq!(LineKind::classify(";日本"), LineKind::Comment(";日本"));
+
+        // The line length limit falls on the second byte of a character, which is dropped whole
+        let long_line: String = format!(";{}", "é".repeat(SMALLEST_MAX_LINE_LENGTH));
+        let mut reader: &[u8] = long_line.as_bytes();
+        assert_eq!(
+            read_bounded_line(&mut reader, SMALLEST_MAX_LINE_LENGTH).unwrap(),
+            Some((format!(";{}", "é".repeat(511)), true))
+        );
+
+        let options: Options = Options {
+            max_line_length: SMALLEST_MAX_LINE_LENGTH,
+            lenient: true,
+            ..Options::default()
+        };
+        let (converted, log) = convert(&format!("{long_line}\n{FIXTURE}"), &options);
+        assert!(converted
+            .unwrap()
+            .contains(&format!("\n;{}\n", "é".repeat(511))));
+        assert!(log.diagnostics.contains(&Diagnostic::LineTruncated {
+            line: 1,
+            max_line_length: SMALLEST_MAX_LINE_LENGTH
+        }));
+    }
+
+    #[test]
+    fn byte_order_mark_is_stripped_from_the_first_line() {
+        let gcode: String = format!(
+            "{UTF8_BOM}; estimated printing time (normal mode) = 1m\r\n{}",
+            FIXTURE
+                .replace("; estimated printing time (normal mode) = 1h 2m 3s\n", "")
+                .replace('\n', "\r\n")
+        );
+
+        let mut log: FileLog = FileLog::default();
+        let (lines, _) = read_lines(gcode.as_bytes(), &Options::default(), &mut log).unwrap();
+        assert_eq!(
+            LineKind::classify(&lines[0]),
+            LineKind::Metadata("estimated printing time (normal mode) = 1m")
+        );
+
+        let (converted, _) = convert(&gcode, &Options::default());
+        let converted: String = converted.unwrap();
+        assert_eq!(
+            header_value(&converted, ANKERMAKE_PRINTING_TIME),
+            Some("60")
+        );
+        assert!(converted.starts_with(";FLAVOR:Marlin\r\n"));
+        assert!(!converted.contains(UTF8_BOM));
+    }
+}
@@ -1,177 +1,305 @@
 #![deny(missing_docs)]
 
-//! A basic post-processor for adding Prusaslicer gcode attributes to the beginning of
-//! gcode files to help the Ankermake M5 printer to correctly estimate print times and
-//! material usage.
+//! Command line wrapper around the post-processor, converting each gcode file, or directory of them, given as an argument
+//! in place.
 
-use std::env::{args, Args};
-use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter, Lines, Write};
+use prusa_to_anker_postprocessor::{
+    expand_directories, format_report_row, process_files, write_csv_summary, BatchOutcome,
+    Diagnostic, FileLog, Options, ANKERMAKE_HEADER_KEYS, REPORT_COLUMNS, SMALLEST_MAX_LINE_LENGTH,
+    STDIO_PATH,
+};
+use std::env::args;
 use std::path::Path;
+use std::process::exit;
 
-/// Prusaslicer attribute for the estimated printing time. Formatted as "XXh YYm ZZs" string
-pub const PRUSA_ESTIMATED_PRINTING_TIME: &str = "estimated printing time";
-/// Prusaslicer attribute for the estimated material usage. Formatted in millimeters, to 2 decimal places
-pub const PRUSA_FILAMENT_USED_MM: &str = "filament used [mm]";
-
-/// Ankermake attribute for the estimated printing time. Formatted as integer number of seconds.
-pub const ANKERMAKE_PRINTING_TIME: &str = "TIME";
-/// Ankermake attribute for the estimated material usage. Formatted in meters to 5 decimal places.
-pub const ANKERMAKE_FILAMENT_USED_M: &str = "Filament used";
-/// The gcode flavour, always Marlin
-pub const ANKERMAKE_FLAVOUR: &str = "FLAVOR";
-
-/// Potential errors that can be encountered while parsing the gcode
-#[derive(Debug)]
-pub enum ParsingError {
-    /// While attempting to extract a value from a line, no value was found
-    MissingValue(String),
-    /// An attempt to parse a string into the specified type failed
-    StringParsingError(&'static str, String),
+/// Take the value following an option that requires one, erroring if the arguments have run out.
+fn next_value(
+    arguments: &mut impl Iterator<Item = String>,
+    option: &str,
+) -> Result<String, String> {
+    arguments
+        .next()
+        .ok_or_else(|| format!("Missing value for \"{option}\""))
 }
 
-/// Selection of fields that we're interested in reformatting for the Ankermake M5 to understand.
-pub enum InterestingFields {
-    /// Time taken to print, represented as seconds
-    Time(u64),
-    /// Amount of filament used during printing, in um x10(0.01 mm)
-    FilamentUsed(u64),
-    /// gcode flavour. always Marlin
-    Flavour(String),
+/// Print everything that was logged about a file, in the order it was logged. Warnings and errors go to stderr, so that
+/// they don't get mixed in with the inspect or report output.
+fn print_log(log: &FileLog) {
+    log.stdout.iter().for_each(|line| println!("{line}"));
+    log.diagnostics
+        .iter()
+        .for_each(|diagnostic| eprintln!("{diagnostic}"));
 }
 
-impl ToString for InterestingFields {
-    fn to_string(&self) -> String {
-        use InterestingFields::*;
-        match self {
-            Time(seconds) => format!(";{}:{}", ANKERMAKE_PRINTING_TIME, seconds),
-            FilamentUsed(length_umx10) => format!(
-                ";{}: {}m",
-                ANKERMAKE_FILAMENT_USED_M,
-                (*length_umx10 as f64) / 100000.0
-            ),
-            Flavour(flavour) => format!(";{}:{}", ANKERMAKE_FLAVOUR, flavour),
+/// Split the command line arguments into the options controlling the conversion and the list of files to convert.
+pub fn parse_arguments(
+    mut arguments: impl Iterator<Item = String>,
+) -> Result<(Options, Vec<String>), String> {
+    let mut options: Options = Options::default();
+    let mut file_paths: Vec<String> = Vec::new();
+
+    while let Some(argument) = arguments.next() {
+        match argument.as_str() {
+            "--filament-unit" => {
+                options.filament_unit = next_value(&mut arguments, &argument)?.parse()?;
+            }
+            "--number-format" => {
+                options.number_format = next_value(&mut arguments, &argument)?.parse()?;
+            }
+            "--max-line-length" => {
+                let length: String = next_value(&mut arguments, &argument)?;
+                options.max_line_length = length
+                    .parse()
+                    .ok()
+                    .filter(|length| *length >= SMALLEST_MAX_LINE_LENGTH)
+                    .ok_or_else(|| {
+                        format!(
+                            "Invalid line length \"{length}\", it must be at least {SMALLEST_MAX_LINE_LENGTH} bytes"
+                        )
+                    })?;
+            }
+            "--time-offset" => {
+                let offset: String = next_value(&mut arguments, &argument)?;
+                options.time_offset = offset
+                    .parse()
+                    .map_err(|_| format!("Invalid time offset \"{offset}\""))?;
+            }
+            "--time-formula" => {
+                options.time_formula = Some(next_value(&mut arguments, &argument)?.parse()?);
+            }
+            "--jobs" | "-j" => {
+                let jobs: String = next_value(&mut arguments, &argument)?;
+                options.jobs = jobs
+                    .parse()
+                    .ok()
+                    .filter(|jobs| *jobs > 0)
+                    .ok_or_else(|| format!("Invalid number of jobs \"{jobs}\""))?;
+            }
+            "--overwrite-policy" => {
+                options.overwrite_policy = next_value(&mut arguments, &argument)?.parse()?;
+            }
+            "--start-gcode" => {
+                let start_gcode_path: String = next_value(&mut arguments, &argument)?;
+                let start_gcode: String =
+                    std::fs::read_to_string(&start_gcode_path).map_err(|start_gcode_error| {
+                        format!(
+                            "Failed to read start gcode at \"{start_gcode_path}\": {start_gcode_error}"
+                        )
+                    })?;
+                options.start_gcode = start_gcode.lines().map(String::from).collect();
+            }
+            "--set" => {
+                let assignment: String = next_value(&mut arguments, &argument)?;
+                let (key, value) = assignment.split_once('=').ok_or_else(|| {
+                    format!("Invalid header field assignment \"{assignment}\", expected KEY=VALUE")
+                })?;
+                let key: &'static str = ANKERMAKE_HEADER_KEYS
+                    .into_iter()
+                    .find(|header_key| *header_key == key)
+                    .ok_or_else(|| {
+                        format!(
+                            "Unknown header field \"{key}\", expected one of {}",
+                            ANKERMAKE_HEADER_KEYS.join(", ")
+                        )
+                    })?;
+                // A later --set for the same key wins
+                options
+                    .header_overrides
+                    .retain(|(set_key, _)| *set_key != key);
+                options.header_overrides.push((key, value.to_string()));
+            }
+            "--output" | "-o" => options.output = Some(next_value(&mut arguments, &argument)?),
+            "--csv" => options.csv = Some(next_value(&mut arguments, &argument)?),
+            "--info-block" => options.info_block = true,
+            "--trace-gcode" => options.trace_gcode = true,
+            "--strip-prusa-config" => options.strip_prusa_config = true,
+            "--filament-weight" => options.filament_weight = true,
+            "--header-sidecar" => options.header_sidecar = true,
+            "--inspect" => options.inspect = true,
+            "--report-only" => options.report_only = true,
+            "--check" => options.check = true,
+            "--verbose" | "-v" => options.verbose = true,
+            "--fast" => options.fast = true,
+            "--recursive" | "-r" => options.recursive = true,
+            "--allow-empty-body" => options.allow_empty_body = true,
+            "--lenient" => options.lenient = true,
+            _ => file_paths.push(argument),
         }
     }
-}
 
-/// Given a line, attempt to parse the value into an integer number of seconds
-pub fn extract_time_data_as_seconds(attribute: &str) -> Result<u64, ParsingError> {
-    // After splitting on the equals sign, skipping the left hand side and trimming the resulting string
-    // we should just have "XXh YYm ZZs"
-    let value = if let Some(string_value) = attribute.split('=').nth(1) {
-        string_value.trim()
-    } else {
-        return Err(ParsingError::MissingValue(attribute.to_string()));
-    };
+    // Every input would be converted into the same output file, each one replacing the last
+    if options.output.is_some() && file_paths.len() > 1 {
+        return Err(format!(
+            "\"--output\" can only be used with a single input file, but {} were given",
+            file_paths.len()
+        ));
+    }
+    if options.output.is_some() && file_paths.iter().any(|path| Path::new(path).is_dir()) {
+        return Err("\"--output\" can't be used with a directory".to_string());
+    }
 
-    let time: u64 = value
-        .split(' ')
-        .map(|value| {
-            if value.ends_with('h') {
-                value.strip_suffix('h').unwrap().parse::<u64>().unwrap() * 60 * 60
-            } else if value.ends_with('m') {
-                value.strip_suffix('m').unwrap().parse::<u64>().unwrap() * 60
-            } else if value.ends_with('s') {
-                value.strip_suffix('s').unwrap().parse::<u64>().unwrap()
-            } else {
-                panic!()
-            }
-        })
-        .sum();
+    // The trace and header sidecar files are named after the input file, which stdin doesn't have
+    if (options.trace_gcode || options.header_sidecar)
+        && file_paths.iter().any(|file_path| file_path == STDIO_PATH)
+    {
+        return Err(
+            "\"--trace-gcode\" and \"--header-sidecar\" can't be used when reading from stdin"
+                .to_string(),
+        );
+    }
+
+    // The inspect output would be mixed in with the converted file
+    if options.inspect && options.output.as_deref() == Some(STDIO_PATH) {
+        return Err("\"--inspect\" can't be used when writing to stdout".to_string());
+    }
 
-    Ok(time)
+    // Likewise the verbose output, and stdin is converted to stdout unless an output is given
+    let writes_to_stdout: bool = match options.output.as_deref() {
+        Some(output) => output == STDIO_PATH,
+        None => file_paths.iter().any(|file_path| file_path == STDIO_PATH),
+    };
+    if options.verbose && writes_to_stdout {
+        return Err("\"--verbose\" can't be used when writing to stdout".to_string());
+    }
+
+    Ok((options, file_paths))
 }
 
-/// Given a line, attempt to extract how many 10s of micrometers of filament are predicted to be used.
-pub fn extract_filament_used_as_um_x10(attribute: &str) -> Result<u64, ParsingError> {
-    // After splitting on the equals sign, skipping the left hand side and trimming the resulting string
-    // we should just have "XXXX.YY", our length in millimeters.
-    let value = if let Some(string_value) = attribute.split('=').nth(1) {
-        string_value.trim()
-    } else {
-        return Err(ParsingError::MissingValue(attribute.to_string()));
+fn main() {
+    // Skip first argument, as that's this program
+    let (options, file_paths) = match parse_arguments(args().skip(1)) {
+        Ok(parsed_arguments) => parsed_arguments,
+        Err(argument_error) => {
+            eprintln!("{argument_error}");
+            exit(1);
+        }
     };
 
-    // Split on the decimal place, then just collect back into a string, which we should be able to parse
-    // into an integer value.
-    let integer_value_str: String = value.split('.').collect();
+    let searching_directories: bool = file_paths.iter().any(|path| Path::new(path).is_dir());
+    let mut search_log: FileLog = FileLog::default();
+    let file_paths: Vec<String> =
+        expand_directories(file_paths, options.recursive, &mut search_log);
+    print_log(&search_log);
 
-    if let Ok(parsed_integer) = integer_value_str.parse() {
-        Ok(parsed_integer)
-    } else {
-        Err(ParsingError::StringParsingError("u64", integer_value_str))
+    if options.report_only {
+        println!("{}", format_report_row(REPORT_COLUMNS));
     }
-}
 
-/// Process the lines in the file, pulling out the attributes that we're interested in and reinserting them in the header for the
-/// file. Returns the new file contents that should be written to the disk.
-pub fn process_lines(lines: Lines<impl BufRead>) -> String {
-    let mut interesting_fields: Vec<InterestingFields> =
-        vec![InterestingFields::Flavour("Marlin".into())];
-
-    // Ditch erroneous lines
-    let lines: Vec<String> = lines.into_iter().flatten().collect();
-
-    lines.iter().for_each(|line| {
-        // Check that our line has enough data on it to have _something_ after skipping the first
-        // 2 bytes
-        if line.len() > 3 {
-            let trimmed_line = &line[2..];
-            if trimmed_line.starts_with(PRUSA_ESTIMATED_PRINTING_TIME) {
-                interesting_fields.push(InterestingFields::Time(
-                    extract_time_data_as_seconds(trimmed_line).unwrap(),
-                ))
-            } else if trimmed_line.starts_with(PRUSA_FILAMENT_USED_MM) {
-                interesting_fields.push(InterestingFields::FilamentUsed(
-                    extract_filament_used_as_um_x10(trimmed_line).unwrap(),
-                ))
-            }
-        }
-    });
+    let outcome: BatchOutcome = process_files(file_paths, &options, print_log);
 
-    let mut file_contents: Vec<String> = interesting_fields
-        .into_iter()
-        .map(|val| val.to_string())
-        .collect();
+    // Kept off stdout, which may be being parsed for the inspect or report output
+    if searching_directories {
+        let already_converted: usize = search_log
+            .diagnostics
+            .iter()
+            .filter(|diagnostic| matches!(diagnostic, Diagnostic::AlreadyConverted(_)))
+            .count();
+        eprintln!(
+            "{} files processed, {} failed, {already_converted} skipped as already converted",
+            outcome.files, outcome.failed_files
+        );
+    }
 
-    file_contents.extend(lines.into_iter());
+    if let Some(csv_path) = &options.csv {
+        if let Err(csv_error) = write_csv_summary(csv_path, &outcome.summaries) {
+            eprintln!("Failed to write CSV summary to \"{csv_path}\": {csv_error}");
+            exit(1);
+        }
+    }
 
-    file_contents.join("\n")
+    if outcome.failed_files > 0 || search_log.has_errors() {
+        exit(1);
+    }
 }
 
-/// Attempt to open the file at the location described in the string, displaying the OS error if the file couldn't be opened for
-/// some reason.
-pub fn process_file(file_path_string: String) {
-    let file_path: &Path = Path::new(&file_path_string);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prusa_to_anker_postprocessor::{process_lines, ANKERMAKE_FLAVOUR};
+    use std::path::PathBuf;
 
-    let file: File = match File::open(file_path) {
-        Ok(file) => file,
-        Err(file_opening_error) => {
-            eprintln!("Failed to open file at \"{file_path_string}\": {file_opening_error:?}");
-            return;
-        }
-    };
+    fn parse(arguments: &[&str]) -> Result<(Options, Vec<String>), String> {
+        parse_arguments(arguments.iter().map(|argument| argument.to_string()))
+    }
 
-    let file_reader: BufReader<File> = BufReader::new(file);
+    #[test]
+    fn rejects_max_line_lengths_below_the_minimum() {
+        assert!(parse(&["--max-line-length", "0", "print.gcode"]).is_err());
+        assert!(parse(&["--max-line-length", "1023", "print.gcode"]).is_err());
 
-    let new_file_contents: String = process_lines(file_reader.lines());
+        let (options, _) = parse(&["--max-line-length", "1024", "print.gcode"]).unwrap();
+        assert_eq!(options.max_line_length, SMALLEST_MAX_LINE_LENGTH);
+    }
 
-    let file: File = match File::create(file_path) {
-        Ok(file) => file,
-        Err(file_opening_error) => {
-            eprintln!("Failed to open file at \"{file_path_string}\": {file_opening_error:?}");
-            return;
-        }
-    };
+    #[test]
+    fn set_overrides_header_fields() {
+        let (options, _) = parse(&["--set", "FLAVOR=Marlin2", "print.gcode"]).unwrap();
+        assert_eq!(
+            options.header_overrides,
+            [(ANKERMAKE_FLAVOUR, "Marlin2".to_string())]
+        );
 
-    let mut file_writer: BufWriter<File> = BufWriter::new(file);
-    file_writer.write_all(new_file_contents.as_bytes()).unwrap();
-}
+        let gcode: &str = "\
+G1 X10 Y10 E1
+; filament used [mm] = 1.00
+; estimated printing time (normal mode) = 1m
+";
+        let mut log: FileLog = FileLog::default();
+        let converted: String = process_lines(gcode.as_bytes(), &options, &mut log).unwrap();
+        assert!(converted.lines().any(|line| line == ";FLAVOR:Marlin2"));
+        assert!(!converted.lines().any(|line| line == ";FLAVOR:Marlin"));
+    }
 
-fn main() {
-    let arguments: Args = args();
+    #[test]
+    fn set_rejects_unknown_header_fields() {
+        let error: String = parse(&["--set", "COLOUR=red", "print.gcode"]).unwrap_err();
+        assert!(error.starts_with("Unknown header field \"COLOUR\""));
+    }
 
-    // Skip first argument, as that's this program
-    arguments.skip(1).for_each(process_file)
+    #[test]
+    fn inspect_output_and_csv_are_all_produced_in_one_run() {
+        let directory: PathBuf =
+            std::env::temp_dir().join(format!("prusa2anker-{}-outputs", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        let input_path: String = directory.join("print.gcode").display().to_string();
+        let output_path: String = directory.join("converted.gcode").display().to_string();
+        let csv_path: String = directory.join("summary.csv").display().to_string();
+        let gcode: &str = "\
+G1 X10 Y10 E1
+; filament used [mm] = 1.00
+; estimated printing time (normal mode) = 1m
+";
+        std::fs::write(&input_path, gcode).unwrap();
+
+        let (options, file_paths) = parse(&[
+            "--inspect",
+            "--output",
+            &output_path,
+            "--csv",
+            &csv_path,
+            &input_path,
+        ])
+        .unwrap();
+        let mut stdout: Vec<String> = Vec::new();
+        let outcome: BatchOutcome = process_files(file_paths, &options, |log: &FileLog| {
+            stdout.extend(log.stdout.iter().cloned())
+        });
+        write_csv_summary(&csv_path, &outcome.summaries).unwrap();
+
+        assert_eq!(outcome.failed_files, 0);
+        assert_eq!(std::fs::read_to_string(&input_path).unwrap(), gcode);
+        assert!(std::fs::read_to_string(&output_path)
+            .unwrap()
+            .lines()
+            .any(|line| line == ";TIME:60"));
+        assert_eq!(stdout.len(), 1);
+        assert!(stdout[0].starts_with('{') && stdout[0].contains("\"time_seconds\":60"));
+        assert_eq!(
+            std::fs::read_to_string(&csv_path).unwrap().lines().count(),
+            2
+        );
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
 }